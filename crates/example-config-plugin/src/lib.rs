@@ -0,0 +1,54 @@
+// ./crates/example-config-plugin/src/lib.rs
+use serde::{Serialize, Deserialize};
+use wasmer_plugin_example::*;
+
+/// The most retries `tune` will allow
+pub const MAX_RETRIES: u8 = 5;
+
+/// Settings for an app that has nothing to
+/// do with mdBook, any type that can be
+/// serialized works with `plugin_helper`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub name: String,
+    pub retries: u8,
+    pub verbose: bool,
+}
+
+#[plugin_helper]
+pub fn tune(mut config: Config) -> Config {
+    // Keep the retries sane and turn on
+    // logging for any debug configs
+    config.retries = config.retries.min(MAX_RETRIES);
+    if config.name.ends_with("-debug") {
+        config.verbose = true;
+    }
+    config
+}
+
+plugin_registry!(tune);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            name: String::from("server-debug"),
+            retries: 20,
+            verbose: false,
+        }
+    }
+    #[test]
+    fn tune_config() {
+        let tuned = tune(config());
+        assert_eq!(tuned.retries, MAX_RETRIES);
+        assert!(tuned.verbose);
+    }
+    #[test]
+    fn ser() {
+        let bytes = revert_data(config());
+        let de: Config = convert_data(&bytes);
+        assert_eq!(tune(de), tune(config()));
+    }
+}
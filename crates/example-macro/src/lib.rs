@@ -3,50 +3,1156 @@
 extern crate proc_macro;
 use proc_macro::TokenStream;
 
-use syn::{Item as SynItem, ItemFn};
-use quote::quote;
+use syn::{
+    parse::Parser,
+    spanned::Spanned,
+    parse_macro_input,
+    punctuated::Punctuated,
+    Token,
+    Item as SynItem, ItemFn, FnArg, ArgCaptured,
+    Attribute, AttributeArgs, NestedMeta, Meta, Lit,
+    ReturnType, Type, TypePath, TypeReference, TypeSlice,
+    PathArguments, GenericArgument,
+    parse_quote,
+};
+use quote::{quote, quote_spanned};
 use proc_macro2::{Ident, Span};
 
+/// Export a function to the host, the function itself is left
+/// as it was. The exports are only built for wasm32 so a plugin
+/// crate still builds and tests natively without any `cfg_attr`
 #[proc_macro_attribute]
-pub fn plugin_helper(_attr: TokenStream, tokens: TokenStream) -> TokenStream {
-    let tokens2 = proc_macro2::TokenStream::from(tokens);
-    let parse2 = syn::parse2::<SynItem>(tokens2).expect("Failed to parse tokens");
-    match parse2 {
-        SynItem::Fn(func) => handle_func(func),
-        _ => panic!("Only functions are currently supported")
+pub fn plugin_helper(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let opts = match parse_opts(args) {
+        Ok(opts) => opts,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let item = parse_macro_input!(tokens as SynItem);
+    match item {
+        SynItem::Fn(func) => handle_func(func, &opts).into(),
+        other => syn::Error::new_spanned(other, "Only functions are currently supported")
+            .to_compile_error()
+            .into(),
+    }
+}
+
+/// Export the function `ident` as the plugin's `_preprocess`,
+/// like `plugin_helper` this is only built for wasm32. It needs to take a
+/// `PreprocessorContext` and a `Book` and return a `Book`,
+/// the exports are the same as `#[plugin_helper]` would add
+#[proc_macro]
+pub fn plugin_main(tokens: TokenStream) -> TokenStream {
+    let ident = parse_macro_input!(tokens as Ident);
+    handle_main(ident).into()
+}
+
+fn handle_main(ident: Ident) -> proc_macro2::TokenStream {
+    // Only the signature is used, the shadow
+    // calls the plugin's own function
+    let func: ItemFn = parse_quote! {
+        fn #ident(ctx: ::mdbook::preprocess::PreprocessorContext, book: ::mdbook::book::Book) -> ::mdbook::book::Book {
+            unreachable!()
+        }
+    };
+    let opts = PluginOpts {
+        export: Some(Ident::new("_preprocess", Span::call_site())),
+        ..PluginOpts::default()
+    };
+    shadow_func(&func, &opts)
+}
+
+/// Export a `__plugin_exports` function that lists the
+/// functions marked with `plugin_helper` passed to it
+/// so a host can check what a plugin offers
+#[proc_macro]
+pub fn plugin_registry(tokens: TokenStream) -> TokenStream {
+    let parser = Punctuated::<Ident, Token![,]>::parse_terminated;
+    match parser.parse(tokens) {
+        Ok(idents) => handle_registry(idents.into_iter().collect()).into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Turn a function returning a sample input into a test that
+/// the input comes back the same after `revert_data` and
+/// `convert_data`, the round trip every plugin call takes.
+/// The function takes no arguments and its return type is the
+/// input type, which needs `PartialEq` and `Debug`
+#[proc_macro_attribute]
+pub fn plugin_roundtrip_test(_attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let func = parse_macro_input!(tokens as ItemFn);
+    handle_roundtrip_test(func).into()
+}
+
+fn handle_roundtrip_test(func: ItemFn) -> proc_macro2::TokenStream {
+    let ty = match &func.decl.output {
+        ReturnType::Type(_, ty) if func.decl.inputs.is_empty() => ty,
+        _ => return syn::Error::new_spanned(&func.decl.fn_token, "plugin_roundtrip_test functions take no arguments and return a sample input")
+            .to_compile_error(),
+    };
+    let ident = &func.ident;
+    let attrs = &func.attrs;
+    let block = &func.block;
+    quote! {
+        #[test]
+        #(#attrs)*
+        fn #ident() {
+            let input: #ty = #block;
+            let bytes = revert_data(&input);
+            let output: #ty = convert_data(&bytes);
+            assert_eq!(output, input);
+        }
+    }
+}
+
+/// The arguments passed to `#[plugin_helper(...)]`
+#[derive(Default)]
+struct PluginOpts {
+    /// The name to export the shadow function as,
+    /// defaults to the function's name with a leading `_`
+    export: Option<Ident>,
+    /// The format used for the input and output
+    format: Format,
+    /// The types to call a generic function with
+    with: Vec<Type>,
+    /// The function doesn't take the context the host sends
+    /// first, it reads it with a generated `plugin_context()`
+    plugin_context: bool,
+    /// The arguments are deserialized borrowing from the
+    /// input, so a `&str` is read without copying it
+    borrow: bool,
+    /// The function takes and returns a string, which is
+    /// passed as is instead of being serialized
+    string: bool,
+}
+
+/// The formats a plugin can use, these pair up
+/// with `wasmer_plugin_example::SerializationFormat`
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Bincode,
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Bincode
+    }
+}
+
+fn parse_opts(args: AttributeArgs) -> Result<PluginOpts, syn::Error> {
+    let mut opts = PluginOpts::default();
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "export" => {
+                match nv.lit {
+                    Lit::Str(ref s) => opts.export = Some(s.parse()?),
+                    ref lit => return Err(syn::Error::new_spanned(lit, "export must be a string literal")),
+                }
+            },
+            NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "with" => {
+                match nv.lit {
+                    Lit::Str(ref s) => {
+                        let tys = s.parse_with(Punctuated::<Type, Token![,]>::parse_terminated)?;
+                        opts.with = tys.into_iter().collect();
+                    },
+                    ref lit => return Err(syn::Error::new_spanned(lit, "with must be a string literal")),
+                }
+            },
+            NestedMeta::Meta(Meta::Word(ref ident)) if ident == "string" => opts.string = true,
+            NestedMeta::Meta(Meta::Word(ref ident)) if ident == "plugin_context" => opts.plugin_context = true,
+            NestedMeta::Meta(Meta::Word(ref ident)) if ident == "borrow" => opts.borrow = true,
+            NestedMeta::Meta(Meta::NameValue(ref nv)) if nv.ident == "format" => {
+                match nv.lit {
+                    Lit::Str(ref s) if s.value() == "bincode" => opts.format = Format::Bincode,
+                    Lit::Str(ref s) if s.value() == "json" => opts.format = Format::Json,
+                    Lit::Str(ref s) if s.value() == "messagepack" => opts.format = Format::MessagePack,
+                    Lit::Str(ref s) if s.value() == "cbor" => opts.format = Format::Cbor,
+                    ref lit => return Err(syn::Error::new_spanned(lit, "format must be \"bincode\", \"json\", \"messagepack\" or \"cbor\"")),
+                }
+            },
+            ref other => return Err(syn::Error::new_spanned(other, "unknown plugin_helper argument")),
+        }
+    }
+    Ok(opts)
+}
+
+fn handle_func(func: ItemFn, opts: &PluginOpts) -> proc_macro2::TokenStream {
+    let shadow = shadow_func(&func, opts);
+    quote! {
+        #func
+
+        #shadow
     }
 }
 
-fn handle_func(func: ItemFn) -> TokenStream {
-    // Check and make sure our function takes
-    // only one argument and panic if not
-    if func.decl.inputs.len() != 1 {
-        panic!("fns marked with plugin_helper can only take 1 argument");
+/// Everything `plugin_helper` adds alongside `func`
+fn shadow_func(func: &ItemFn, opts: &PluginOpts) -> proc_macro2::TokenStream {
+    // Pull out the type of each argument so the
+    // deserialized values are handed over with the
+    // right types, borrowed arguments are deserialized
+    // as their owned type
+    let mut input_tys = Vec::new();
+    let mut borrows = Vec::new();
+    for arg in func.decl.inputs.iter() {
+        let (ty, borrow) = match arg {
+            FnArg::Captured(ArgCaptured { ty, .. }) if opts.borrow => borrowed_input(ty),
+            FnArg::Captured(ArgCaptured { ty, .. }) => owned_input(ty),
+            other => return syn::Error::new_spanned(other, "fns marked with plugin_helper must take typed arguments, not self")
+                .to_compile_error(),
+        };
+        input_tys.push(ty);
+        borrows.push(borrow);
     }
+    // Only bincode can borrow every string, JSON can't
+    // borrow one with escapes. The arguments are only
+    // borrowed inside the shadow so their lifetimes
+    // have to be left to it
+    if opts.borrow {
+        if opts.format != Format::Bincode {
+            return syn::Error::new_spanned(&func.decl.fn_token, "plugin_helper(borrow) only works with bincode")
+                .to_compile_error();
+        }
+        if let Some(lifetime) = func.decl.generics.lifetimes().next() {
+            return syn::Error::new_spanned(lifetime, "plugin_helper(borrow) functions need to leave their lifetimes out, e.g. Chapter<'_>")
+                .to_compile_error();
+        }
+    }
+    // The host still sends the context first, it's
+    // read like any other argument but kept for
+    // `plugin_context()` instead of being passed on
+    let skipped = if opts.plugin_context {
+        if input_tys.is_empty() {
+            return syn::Error::new_spanned(&func.decl.fn_token, "plugin_helper(plugin_context) functions take the arguments sent after the context, e.g. a Book")
+                .to_compile_error();
+        }
+        input_tys.insert(0, parse_quote!(::mdbook::preprocess::PreprocessorContext));
+        borrows.insert(0, false);
+        1
+    } else {
+        0
+    };
     // Copy this function's identifier
     let ident = func.ident.clone();
-    // Create a new identifier with a underscore in front of 
+    // The function is re-emitted as it was written, keeping its
+    // visibility, docs and attributes. The shadow needs to be
+    // `pub` to be exported but should share any `cfg`s so it
+    // doesn't outlive the function it calls. The proc macro
+    // always runs on the host so the target can only be checked
+    // in what it emits, a native build skips every export
+    let wasm_only: Attribute = parse_quote!(#[cfg(target_arch = "wasm32")]);
+    let cfgs: Vec<_> = Some(wasm_only).into_iter()
+        .chain(func.attrs.iter()
+            .filter(|attr| attr.path.segments.len() == 1 && attr.path.segments[0].ident == "cfg")
+            .cloned())
+        .collect();
+    // Pick the helpers for the requested format
+    let (convert, revert) = match opts.format {
+        Format::Bincode => (quote! { convert_data }, quote! { revert_data_into }),
+        Format::Json => (quote! { convert_data_json }, quote! { revert_data_json_into }),
+        Format::MessagePack => (quote! { convert_data_msgpack }, quote! { revert_data_msgpack_into }),
+        Format::Cbor => (quote! { convert_data_cbor }, quote! { revert_data_cbor_into }),
+    };
+    // The bytes the host wrote for us, still compressed
+    // if the host was built with `compress`
+    let read = quote! {
+        let value: &[u8] = unsafe {
+            ::std::slice::from_raw_parts(ptr as _, len as _)
+        };
+    };
+    // A generic function can't be exported as is, the
+    // types to call it with come from `with`
+    let generic = func.decl.generics.type_params().next().is_some();
+    let callee = if opts.with.is_empty() {
+        if generic {
+            return syn::Error::new_spanned(&func.decl.generics, "generic plugin functions need the types to call them with, e.g. #[plugin_helper(with = \"MyType\")]")
+                .to_compile_error();
+        }
+        quote! { #ident }
+    } else {
+        let with = &opts.with;
+        quote! { #ident::<#(#with),*> }
+    };
+    // With no arguments there is nothing to read, a single
+    // argument is deserialized as is, more than one are
+    // deserialized as a tuple and then destructured in
+    // order when calling the original function
+    let (decode, call) = if input_tys.is_empty() {
+        (
+            quote! { let _ = (ptr, len); },
+            quote! { #callee() },
+        )
+    } else if input_tys.len() == 1 && !opts.plugin_context {
+        let input_ty = &input_tys[0];
+        let arg = if borrows[0] { quote! { &arg } } else { quote! { arg } };
+        (
+            quote! { #read let arg: #input_ty = #convert(&value); },
+            quote! { #callee(#arg) },
+        )
+    } else {
+        let args: Vec<_> = (0..input_tys.len())
+            .map(|i| Ident::new(&format!("arg{}", i), Span::call_site()))
+            .collect();
+        let args2: Vec<_> = args.iter().zip(&borrows)
+            .skip(skipped)
+            .map(|(arg, borrow)| if *borrow { quote! { &#arg } } else { quote! { #arg } })
+            .collect();
+        let tys = &input_tys;
+        let keep = if opts.plugin_context {
+            let ctx = &args[0];
+            quote! { set_plugin_context(#ctx); }
+        } else {
+            quote! {}
+        };
+        (
+            quote! { #read let (#(#args,)*): (#(#tys,)*) = #convert(&value); #keep },
+            quote! { #callee(#(#args2),*) },
+        )
+    };
+    // Name the requirement on each argument's type at the
+    // argument itself, otherwise a type that can't be
+    // deserialized is only reported deep in the shadow
+    let check = if opts.borrow {
+        quote! { input_must_implement_deserialize_borrowed }
+    } else {
+        quote! { input_must_implement_deserialize }
+    };
+    let checks: Vec<_> = input_tys.iter()
+        .skip(skipped)
+        .zip(func.decl.inputs.iter())
+        .map(|(ty, arg)| quote_spanned! { arg.span()=>
+            #check::<#ty>();
+        })
+        .collect();
+    // A panic's message is kept for the host to read
+    // after the trap, this needs std's panic hooks
+    let hook = if cfg!(feature = "no_std") {
+        quote! {}
+    } else {
+        quote! { install_panic_hook(); }
+    };
+    let decode = quote! { #hook #(#checks)* #decode };
+    // An `async fn` is driven to completion before
+    // its output is serialized
+    let call = if func.asyncness.is_some() {
+        if cfg!(feature = "no_std") {
+            return syn::Error::new_spanned(&func.asyncness, "async plugin functions need std, disable the no_std feature")
+                .to_compile_error();
+        }
+        quote! { block_on(#call) }
+    } else {
+        call
+    };
+    // Every shadow hands back a `Result<T, String>`, if the
+    // function already returns a `Result` we just need to
+    // turn its error into a `String`, otherwise the value
+    // is always `Ok`
+    let wrap = if returns_result(&func.decl.output) {
+        quote! {
+            let ret: ::std::result::Result<_, ::std::string::String> = ret.map_err(|e| e.to_string());
+        }
+    } else {
+        quote! {
+            let ret: ::std::result::Result<_, ::std::string::String> = ::std::result::Result::Ok(ret);
+        }
+    };
+    // A `PluginOutput` is unwrapped, when it is `Unchanged`
+    // the host is told to keep its copy of the input
+    let bind = if returns_named(&func.decl.output, "PluginOutput") {
+        quote! {
+            let ret = match #call {
+                PluginOutput::Changed(ret) => ret,
+                PluginOutput::Unchanged => {
+                    unsafe {
+                        MemoryLayout::write_len_raw(MemoryLayout::UNCHANGED);
+                    }
+                    return 0;
+                },
+            };
+        }
+    } else {
+        quote! { let ret = #call; }
+    };
+    // Use the export name if one was provided, otherwise
+    // create a new identifier with a underscore in front of 
     // the original identifier
-    let shadows_ident = Ident::new(&format!("_{}", ident), Span::call_site());
-    // Generate some rust with the original and new
-    // shadowed function
-    let ret = quote! {
-        #func
+    let shadows_ident = opts.export.clone().unwrap_or_else(|| {
+        Ident::new(&format!("_{}", ident), Span::call_site())
+    });
+    // Describe this function for `plugin_registry!`
+    let registry_ident = registry_ident(&ident);
+    let export_name = shadows_ident.to_string();
+    let input_name = match input_tys.len() {
+        0 => "()".to_string(),
+        1 => type_name(&input_tys[0]),
+        _ => {
+            let tys = &input_tys;
+            type_name(&quote! { (#(#tys),*) })
+        },
+    };
+    let output_name = match &func.decl.output {
+        ReturnType::Default => "()".to_string(),
+        ReturnType::Type(_, ty) => type_name(ty),
+    };
+    let registry_cfgs = &cfgs;
+    let registry = quote! {
+        #(#registry_cfgs)*
+        #[doc(hidden)]
+        pub const #registry_ident: PluginExport = PluginExport::new(#export_name, #input_name, #output_name);
+    };
+    // A host sending its input in chunks calls this once
+    // they have all been fed in with `__plugin_feed_chunk`
+    let finish_ident = Ident::new(&format!("__plugin_finish{}", shadows_ident), Span::call_site());
+    let finish_cfgs = &cfgs;
+    let finish = quote! {
+        #(#finish_cfgs)*
+        #[no_mangle]
+        pub fn #finish_ident() -> i32 {
+            let bytes = take_chunks();
+            #shadows_ident(bytes.as_ptr() as i32, bytes.len() as u32)
+        }
+    };
+    // A host can compare the type it is about to send with
+    // this before calling, the name is left in memory the
+    // same way an output is
+    let input_type_ident = Ident::new(&format!("__input_type_of{}", shadows_ident), Span::call_site());
+    let input_type_cfgs = &cfgs;
+    let input_type = quote! {
+        #(#input_type_cfgs)*
+        #[no_mangle]
+        pub fn #input_type_ident() -> i32 {
+            const NAME: &str = #input_name;
+            unsafe {
+                MemoryLayout::write_len_raw(NAME.len() as u32);
+            }
+            NAME.as_ptr() as i32
+        }
+    };
+    // A host checks for this marker to know the function's
+    // output is a `PluginReturn` instead of only the book
+    let returns = if returns_plugin_return(&func.decl.output) {
+        let returns_ident = Ident::new(&format!("__plugin_return{}", shadows_ident), Span::call_site());
+        marker_export(&returns_ident, &cfgs)
+    } else {
+        quote! {}
+    };
+    // What the function calls to read the context the host
+    // sent, this is built for every target so the function
+    // can still be called natively once a test has set one
+    // with `set_plugin_context`
+    let accessor = if opts.plugin_context {
+        let accessor_cfgs = &cfgs[1..];
+        quote! {
+            #(#accessor_cfgs)*
+            /// A copy of the context the host sent with this call
+            pub fn plugin_context() -> ::mdbook::preprocess::PreprocessorContext {
+                with_plugin_context(::mdbook::preprocess::PreprocessorContext::clone)
+                    .expect("plugin_context is only set once the host calls the plugin")
+            }
+        }
+    } else {
+        quote! {}
+    };
+    // Everything emitted alongside the shadow, whichever
+    // way it reads its input and writes its output
+    let items = quote! {
+        #registry
+
+        #finish
+
+        #input_type
+
+        #returns
+
+        #accessor
+    };
+    // A string is read and written as UTF-8, skipping serde
+    // and compression entirely. The output is handed to the
+    // host the same way and an empty string has length 0
+    if opts.string {
+        if input_name != "String" || output_name != "String" {
+            return syn::Error::new_spanned(&func.decl.fn_token, "plugin_helper(string) functions must take a String or &str and return a String")
+                .to_compile_error();
+        }
+        return quote! {
+            #items
 
+            #(#cfgs)*
+            #[no_mangle]
+            pub fn #shadows_ident(ptr: i32, len: u32) -> i32 {
+                #hook
+                let value: &[u8] = unsafe {
+                    ::std::slice::from_raw_parts(ptr as _, len as _)
+                };
+                let arg = ::std::str::from_utf8(value)
+                    .expect("Input is not UTF-8")
+                    .to_string();
+                let ret: ::std::string::String = #call;
+                let bytes = ret.into_bytes().into_boxed_slice();
+                unsafe {
+                    MemoryLayout::write_len_raw(bytes.len() as u32);
+                }
+                Box::into_raw(bytes) as *mut u8 as i32
+            }
+        };
+    }
+    // A function that only has side effects doesn't have
+    // anything to send back, so we write a length of 0 to
+    // let the host know there is no output
+    if returns_unit(&func.decl.output) {
+        return quote! {
+            #items
+
+            #(#cfgs)*
+            #[no_mangle]
+            pub fn #shadows_ident(ptr: i32, len: u32) -> i32 {
+                #decode
+                #call;
+                unsafe {
+                    MemoryLayout::write_len_raw(0);
+                }
+                0
+            }
+        };
+    }
+    // Generate the new shadowed function
+    quote! {
+        #items
+
+        #(#cfgs)*
         #[no_mangle]
         pub fn #shadows_ident(ptr: i32, len: u32) -> i32 {
-            let value: &[u8] = unsafe {
-                ::std::slice::from_raw_parts(ptr as _, len as _)
-            };
-            let arg = convert_data(value);
-            let ret = #ident(arg);
-            let bytes = revert_data(&ret);
+            // The input region was reserved by the host with
+            // `__plugin_alloc` and stays the host's to free
+            #decode
+            #bind
+            #wrap
+            // Serialize into the shared scratch buffer and box
+            // straight from it, the only allocation this needs
+            let bytes: Box<[u8]> = with_scratch(|buf| {
+                #revert(&ret, buf);
+                Box::from(buf.as_slice())
+            });
             let len = bytes.len() as u32;
+            // Write the length of our output into
+            // memory for the host to read
             unsafe {
-                ::std::ptr::write(1 as _, len);
+                MemoryLayout::write_len_raw(len);
             }
-            bytes.as_ptr() as _
+            // Hand ownership of the output buffer over
+            // to the host, which must release it with
+            // `__plugin_free` once it has read the result
+            // back out. If we let it drop here the pointer
+            // we return would be dangling
+            Box::into_raw(bytes) as *mut u8 as i32
         }
+    }
+}
+
+/// An empty export a host can look for by `name`, sharing
+/// the shadow's `cfgs` so it is only there when the shadow is
+fn marker_export(name: &Ident, cfgs: &[Attribute]) -> proc_macro2::TokenStream {
+    quote! {
+        #(#cfgs)*
+        #[no_mangle]
+        pub fn #name() {}
+    }
+}
+
+fn handle_registry(idents: Vec<Ident>) -> proc_macro2::TokenStream {
+    let consts = idents.iter().map(registry_ident);
+    quote! {
+        #[cfg(target_arch = "wasm32")]
+        #[no_mangle]
+        pub fn __plugin_exports(ptr: i32, len: u32) -> i32 {
+            let _ = (ptr, len);
+            let exports: &[PluginExport] = &[#(#consts),*];
+            let ret: ::std::result::Result<_, ::std::string::String> = ::std::result::Result::Ok(exports);
+            // The registry is handed over just like
+            // the output of any other plugin function
+            let bytes: Box<[u8]> = with_scratch(|buf| {
+                revert_data_into(&ret, buf);
+                Box::from(buf.as_slice())
+            });
+            unsafe {
+                MemoryLayout::write_len_raw(bytes.len() as u32);
+            }
+            Box::into_raw(bytes) as *mut u8 as i32
+        }
+    }
+}
+
+/// The constant `plugin_helper` describes `ident` with
+fn registry_ident(ident: &Ident) -> Ident {
+    Ident::new(&format!("__PLUGIN_EXPORT_{}", ident.to_string().to_uppercase()), Span::call_site())
+}
+
+/// A type as written, without the spacing
+/// `to_string` puts between tokens
+fn type_name<T: quote::ToTokens>(ty: &T) -> String {
+    quote! { #ty }.to_string().replace(' ', "")
+}
+
+/// The type to deserialize an argument as and if the
+/// function wants a borrow of it, `&[T]` is read as a
+/// `Vec<T>`, `&str` as a `String` and `&T` as a `T`
+fn owned_input(ty: &Type) -> (Type, bool) {
+    match ty {
+        Type::Reference(TypeReference { elem, mutability: None, .. }) => match &**elem {
+            Type::Slice(TypeSlice { elem, .. }) => (parse_quote!(Vec<#elem>), true),
+            Type::Path(TypePath { path, qself: None })
+                if path.segments.len() == 1 && path.segments[0].ident == "str" => (parse_quote!(String), true),
+            other => (other.clone(), true),
+        },
+        other => (other.clone(), false),
+    }
+}
+
+/// The type to deserialize an argument as with
+/// `plugin_helper(borrow)`, a `&str` or `&[u8]` is read
+/// borrowing from the input and anything else is read
+/// like `owned_input` would. Borrows inside of a type,
+/// like a `Vec<&str>`, are always kept
+fn borrowed_input(ty: &Type) -> (Type, bool) {
+    if let Type::Reference(TypeReference { elem, mutability: None, .. }) = ty {
+        let borrowable = match &**elem {
+            Type::Slice(TypeSlice { elem, .. }) => type_name(elem) == "u8",
+            Type::Path(TypePath { path, qself: None }) => path.segments.len() == 1 && path.segments[0].ident == "str",
+            _ => false,
+        };
+        if borrowable {
+            return (ty.clone(), false);
+        }
+    }
+    owned_input(ty)
+}
+
+/// Check if a function returns `()`, either by leaving
+/// off the return type or spelling it out
+fn returns_unit(output: &ReturnType) -> bool {
+    match output {
+        ReturnType::Default => true,
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::Tuple(tuple) => tuple.elems.is_empty(),
+            _ => false,
+        },
+    }
+}
+
+/// Check if a function's return type is spelled `Result<...>`
+fn returns_result(output: &ReturnType) -> bool {
+    returns_named(output, "Result")
+}
+
+/// Check if a function's return type is spelled `name<...>`
+fn returns_named(output: &ReturnType, name: &str) -> bool {
+    match output {
+        ReturnType::Type(_, ty) => named(ty, name),
+        ReturnType::Default => false,
+    }
+}
+
+/// Check if a type is spelled `name<...>`
+fn named(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(TypePath { path, .. }) => path.segments
+            .last()
+            .map(|seg| seg.value().ident == name)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Check if a function returns a `PluginReturn`, on
+/// its own or as the `Ok` of a `Result`
+fn returns_plugin_return(output: &ReturnType) -> bool {
+    let ty = match output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => return false,
+    };
+    if named(ty, "PluginReturn") {
+        return true;
+    }
+    let args = match &**ty {
+        Type::Path(TypePath { path, .. }) if named(ty, "Result") => path.segments.last()
+            .map(|seg| seg.into_value().arguments.clone()),
+        _ => None,
     };
-    ret.into()
-}
\ No newline at end of file
+    match args {
+        Some(PathArguments::AngleBracketed(args)) => match args.args.first().map(|arg| arg.into_value().clone()) {
+            Some(GenericArgument::Type(ok)) => named(&ok, "PluginReturn"),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_input_from_memory() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                let u = pair.0.wrapping_mul(2);
+                (u, pair.1.repeat(2))
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("convert_data ( & value )"));
+        assert!(out.contains("let arg : ( u8 , String ) ="));
+        assert!(!out.contains("attributed"));
+    }
+
+    #[test]
+    fn returns_output_pointer() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub fn _multiply ( ptr : i32 , len : u32 ) -> i32"));
+        assert!(out.contains("revert_data_into ( & ret , buf ) ;"));
+        assert!(out.contains("Box :: into_raw ( bytes )"));
+    }
+
+    #[test]
+    fn single_arg() {
+        let func: ItemFn = parse_quote! {
+            pub fn preprocess(book: Book) -> Book {
+                book
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("let arg : Book = convert_data ( & value ) ;"));
+        assert!(out.contains("let ret = preprocess ( arg ) ;"));
+        assert!(!out.contains("arg0"));
+    }
+
+    #[test]
+    fn checks_inputs() {
+        let func: ItemFn = parse_quote! {
+            pub fn count(items: &[Chapter], limit: u8) -> usize {
+                items.len()
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("input_must_implement_deserialize :: < Vec < Chapter > > ( ) ;"));
+        assert!(out.contains("input_must_implement_deserialize :: < u8 > ( ) ;"));
+    }
+
+    #[test]
+    fn borrowed_args() {
+        let func: ItemFn = parse_quote! {
+            pub fn count(items: &[Chapter]) -> usize {
+                items.len()
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("let arg : Vec < Chapter > = convert_data ( & value ) ;"));
+        assert!(out.contains("let ret = count ( & arg ) ;"));
+        let func: ItemFn = parse_quote! {
+            pub fn find(name: &str, book: &Book, limit: u8) -> bool {
+                true
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains(": ( String , Book , u8 , ) = convert_data ( & value ) ;"));
+        assert!(out.contains("let ret = find ( & arg0 , & arg1 , arg2 ) ;"));
+    }
+
+    #[test]
+    fn chunked_finish() {
+        let func: ItemFn = parse_quote! {
+            pub fn preprocess(book: Book) -> Book {
+                book
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub fn __plugin_finish_preprocess ( ) -> i32"));
+        assert!(out.contains("_preprocess ( bytes . as_ptr ( ) as i32 , bytes . len ( ) as u32 )"));
+    }
+
+    #[test]
+    fn multiple_args() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(by: u8, s: String) -> String {
+                s.repeat(by as usize)
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("let ( arg0 , arg1 , ) : ( u8 , String , ) = convert_data ( & value ) ;"));
+        assert!(out.contains("let ret = multiply ( arg0 , arg1 ) ;"));
+    }
+
+    #[test]
+    fn wraps_in_ok() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains(":: std :: result :: Result :: Ok ( ret )"));
+    }
+
+    #[test]
+    fn passes_result_through() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> Result<(u8, String), String> {
+                Err(pair.1)
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("ret . map_err ( | e | e . to_string ( ) )"));
+        assert!(!out.contains(":: std :: result :: Result :: Ok ( ret )"));
+    }
+
+    #[test]
+    fn unchanged_output() {
+        let func: ItemFn = parse_quote! {
+            pub fn preprocess(book: Book) -> PluginOutput<Book> {
+                PluginOutput::Unchanged
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("let ret = match preprocess ( arg ) { PluginOutput :: Changed ( ret ) => ret ,"));
+        assert!(out.contains("MemoryLayout :: write_len_raw ( MemoryLayout :: UNCHANGED ) ;"));
+        assert!(out.contains(":: std :: result :: Result :: Ok ( ret )"));
+    }
+
+    #[test]
+    fn no_args() {
+        let func: ItemFn = parse_quote! {
+            pub fn default_pair() -> (u8, String) {
+                (2, String::from("attributed"))
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub fn _default_pair ( ptr : i32 , len : u32 ) -> i32"));
+        assert!(out.contains("let ret = default_pair ( ) ;"));
+        assert!(!out.contains("convert_data"));
+    }
+
+    #[test]
+    fn unit_return() {
+        let func: ItemFn = parse_quote! {
+            pub fn log(msg: String) {
+                let _ = msg;
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("MemoryLayout :: write_len_raw ( 0 )"));
+        assert!(!out.contains("revert_data"));
+        let func: ItemFn = parse_quote! {
+            pub fn log(msg: String) -> () {
+                let _ = msg;
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(!out.contains("revert_data"));
+    }
+
+    #[test]
+    fn async_fn() {
+        let func: ItemFn = parse_quote! {
+            pub async fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        if cfg!(feature = "no_std") {
+            assert!(out.contains("compile_error"));
+        } else {
+            assert!(out.contains("pub async fn multiply"));
+            assert!(out.contains("let ret = block_on ( multiply ( arg ) ) ;"));
+        }
+    }
+
+    #[test]
+    fn panic_hook() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        if cfg!(feature = "no_std") {
+            assert!(!out.contains("install_panic_hook"));
+        } else {
+            assert!(out.contains("pub fn _multiply ( ptr : i32 , len : u32 ) -> i32 { install_panic_hook ( ) ;"));
+        }
+    }
+
+    #[test]
+    fn keeps_attributes() {
+        let func: ItemFn = parse_quote! {
+            /// Doubles things
+            #[inline]
+            #[cfg(feature = "multiply")]
+            pub(crate) fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("# [ doc = \" Doubles things\" ]"));
+        assert!(out.contains("# [ inline ]"));
+        assert!(out.contains("pub ( crate ) fn multiply"));
+        // Each export only picks up the cfg, after the one
+        // limiting it to wasm32
+        let shared = "# [ cfg ( target_arch = \"wasm32\" ) ] # [ cfg ( feature = \"multiply\" ) ]";
+        assert_eq!(out.matches("# [ cfg ( feature = \"multiply\" ) ]").count(), out.matches(shared).count() + 1);
+        assert!(out.contains(&format!("{} # [ no_mangle ] pub fn _multiply", shared)));
+        assert_eq!(out.matches("# [ inline ]").count(), 1);
+        assert!(out.contains("# [ no_mangle ] pub fn _multiply"));
+    }
+
+    #[test]
+    fn wasm_only() {
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        // The function itself is left for native builds
+        assert!(out.starts_with("pub fn multiply"));
+        let items = out.matches("# [ no_mangle ]").count() + out.matches("pub const").count();
+        assert_eq!(out.matches("# [ cfg ( target_arch = \"wasm32\" ) ]").count(), items);
+        let registry = handle_registry(vec![Ident::new("multiply", Span::call_site())]).to_string();
+        assert!(registry.starts_with("# [ cfg ( target_arch = \"wasm32\" ) ] # [ no_mangle ] pub fn __plugin_exports"));
+    }
+
+    #[test]
+    fn export_name() {
+        let opts = parse_opts(vec![parse_quote!(export = "run")]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &opts).to_string();
+        assert!(out.contains("pub fn run ( ptr : i32 , len : u32 ) -> i32"));
+        assert!(!out.contains("_multiply"));
+    }
+
+    #[test]
+    fn json_format() {
+        let opts = parse_opts(vec![parse_quote!(format = "json")]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &opts).to_string();
+        assert!(out.contains("convert_data_json ( & value )"));
+        assert!(out.contains("revert_data_json_into ( & ret , buf )"));
+    }
+
+    #[test]
+    fn cbor_format() {
+        let opts = parse_opts(vec![parse_quote!(format = "cbor")]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(func, &opts).to_string();
+        assert!(out.contains("convert_data_cbor ( & value )"));
+        assert!(out.contains("revert_data_cbor_into ( & ret , buf )"));
+    }
+
+    #[test]
+    fn registry() {
+        let preprocess: ItemFn = parse_quote! {
+            pub fn preprocess(ctx: PreprocessorContext, book: Book) -> Book {
+                book
+            }
+        };
+        let multiply: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        let out = handle_func(preprocess, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub const __PLUGIN_EXPORT_PREPROCESS : PluginExport = PluginExport :: new ( \"_preprocess\" , \"(PreprocessorContext,Book)\" , \"Book\" )"));
+        let out = handle_func(multiply, &PluginOpts::default()).to_string();
+        assert!(out.contains("PluginExport :: new ( \"_multiply\" , \"(u8,String)\" , \"(u8,String)\" )"));
+        let idents = vec![
+            Ident::new("preprocess", Span::call_site()),
+            Ident::new("multiply", Span::call_site()),
+        ];
+        let out = handle_registry(idents).to_string();
+        assert!(out.contains("pub fn __plugin_exports ( ptr : i32 , len : u32 ) -> i32"));
+        assert!(out.contains("& [ __PLUGIN_EXPORT_PREPROCESS , __PLUGIN_EXPORT_MULTIPLY ]"));
+    }
+
+    #[test]
+    fn generic_with() {
+        let opts = parse_opts(vec![parse_quote!(with = "Shout")]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn transform<T: Transformer>(book: Book) -> Book {
+                T::transform(book)
+            }
+        };
+        let out = handle_func(func.clone(), &opts).to_string();
+        assert!(out.contains("let ret = transform :: < Shout > ( arg ) ;"));
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("compile_error"));
+        // Non-generic functions are left alone
+        let func: ItemFn = parse_quote! {
+            pub fn preprocess(book: Book) -> Book {
+                book
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("let ret = preprocess ( arg ) ;"));
+    }
+
+    #[test]
+    fn input_type() {
+        let func: ItemFn = parse_quote! {
+            pub fn preprocess(ctx: &PreprocessorContext, book: Book) -> Book {
+                book
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub fn __input_type_of_preprocess ( ) -> i32"));
+        assert!(out.contains("const NAME : & str = \"(PreprocessorContext,Book)\" ;"));
+    }
+
+    #[test]
+    fn plugin_return() {
+        let func: ItemFn = parse_quote! {
+            pub fn lint(ctx: PreprocessorContext, book: Book) -> PluginReturn<Book, PreprocessorContext> {
+                PluginReturn::new(book)
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub fn __plugin_return_lint ( ) { }"));
+        let func: ItemFn = parse_quote! {
+            pub fn lint(ctx: PreprocessorContext, book: Book) -> Result<PluginReturn<Book, PreprocessorContext>, String> {
+                Ok(PluginReturn::new(book))
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains("pub fn __plugin_return_lint ( ) { }"));
+        let func: ItemFn = parse_quote! {
+            pub fn lint(ctx: PreprocessorContext, book: Book) -> Book {
+                book
+            }
+        };
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(!out.contains("__plugin_return"));
+    }
+
+    #[test]
+    fn plugin_context() {
+        let opts = parse_opts(vec![parse_quote!(plugin_context)]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn stamp(book: Book) -> Book {
+                book
+            }
+        };
+        let out = handle_func(func.clone(), &opts).to_string();
+        assert!(out.contains("set_plugin_context ( arg0 ) ;"));
+        assert!(out.contains("let ret = stamp ( arg1 ) ;"));
+        assert!(out.contains("pub fn plugin_context ( ) -> :: mdbook :: preprocess :: PreprocessorContext"));
+        // The host sends the context as it always does
+        assert!(out.contains("const NAME : & str = \"(::mdbook::preprocess::PreprocessorContext,Book)\" ;"));
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(!out.contains("plugin_context"));
+        let func: ItemFn = parse_quote! {
+            pub fn nothing() -> Book {
+                Book::new()
+            }
+        };
+        assert!(handle_func(func, &opts).to_string().contains("compile_error"));
+    }
+
+    #[test]
+    fn borrow() {
+        let opts = parse_opts(vec![parse_quote!(borrow)]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn count(content: &str, names: Vec<&str>, book: &Book) -> usize {
+                content.len()
+            }
+        };
+        let out = handle_func(func.clone(), &opts).to_string();
+        assert!(out.contains("let ( arg0 , arg1 , arg2 , ) : ( & str , Vec < & str > , Book , ) = convert_data ( & value ) ;"));
+        assert!(out.contains("let ret = count ( arg0 , arg1 , & arg2 ) ;"));
+        assert!(out.contains("input_must_implement_deserialize_borrowed :: < & str > ( ) ;"));
+        // Without it the string is copied into a `String`
+        let out = handle_func(func, &PluginOpts::default()).to_string();
+        assert!(out.contains(": ( String , Vec < & str > , Book , )"));
+        let opts = parse_opts(vec![parse_quote!(borrow), parse_quote!(format = "json")]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn count(content: &str) -> usize {
+                content.len()
+            }
+        };
+        assert!(handle_func(func, &opts).to_string().contains("compile_error"));
+    }
+
+    #[test]
+    fn string() {
+        let opts = parse_opts(vec![parse_quote!(string)]).unwrap();
+        let func: ItemFn = parse_quote! {
+            pub fn wasm_to_wasm(s: &str) -> String {
+                s.replace("WASM", "Wasm")
+            }
+        };
+        let out = handle_func(func, &opts).to_string();
+        assert!(out.contains("let ret : :: std :: string :: String = wasm_to_wasm ( & arg ) ;"));
+        assert!(!out.contains("convert_data"));
+        assert!(!out.contains("revert_data"));
+        assert!(!out.contains("compress"));
+        let func: ItemFn = parse_quote! {
+            pub fn multiply(pair: (u8, String)) -> (u8, String) {
+                pair
+            }
+        };
+        assert!(handle_func(func, &opts).to_string().contains("compile_error"));
+    }
+
+    #[test]
+    fn plugin_main() {
+        let out = handle_main(Ident::new("run", Span::call_site())).to_string();
+        assert!(out.contains("# [ cfg ( target_arch = \"wasm32\" ) ] # [ no_mangle ] pub fn _preprocess ( ptr : i32 , len : u32 ) -> i32"));
+        assert!(out.contains("let ret = run ( arg0 , arg1 ) ;"));
+        assert!(out.contains("pub const __PLUGIN_EXPORT_RUN : PluginExport"));
+        // The function itself isn't emitted again
+        assert!(!out.contains("unreachable"));
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let func: ItemFn = parse_quote! {
+            fn pair() -> (u8, String) {
+                (2, String::from("attributed"))
+            }
+        };
+        let out = handle_roundtrip_test(func).to_string();
+        assert!(out.contains("# [ test ] fn pair ( )"));
+        assert!(out.contains("let input : ( u8 , String ) = { ( 2 , String :: from ( \"attributed\" ) ) } ;"));
+        assert!(out.contains("let output : ( u8 , String ) = convert_data ( & bytes ) ;"));
+        let func: ItemFn = parse_quote! {
+            fn pair(u: u8) -> (u8, String) {
+                (u, String::new())
+            }
+        };
+        assert!(handle_roundtrip_test(func).to_string().contains("compile_error"));
+    }
+
+    #[test]
+    fn unknown_arg() {
+        assert!(parse_opts(vec![parse_quote!(name = "run")]).is_err());
+        assert!(parse_opts(vec![parse_quote!(export = 1)]).is_err());
+        assert!(parse_opts(vec![parse_quote!(format = "yaml")]).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+// ./crates/example-macro/tests/ui.rs
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
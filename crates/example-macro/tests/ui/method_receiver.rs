@@ -0,0 +1,9 @@
+// ./crates/example-macro/tests/ui/method_receiver.rs
+use wasmer_plugin_example::*;
+
+#[plugin_helper]
+pub fn count(&self) -> u8 {
+    0
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+// ./crates/example-macro/tests/ui/not_a_fn.rs
+use example_macro::plugin_helper;
+
+#[plugin_helper]
+struct NotAFn;
+
+fn main() {}
@@ -0,0 +1,12 @@
+// ./crates/example-macro/tests/ui/not_deserialize.rs
+use wasmer_plugin_example::*;
+
+struct NotSerde;
+
+#[plugin_helper]
+pub fn count(input: NotSerde) -> u8 {
+    let _ = input;
+    0
+}
+
+fn main() {}
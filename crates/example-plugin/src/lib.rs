@@ -5,10 +5,16 @@ use mdbook::{
         Book,
         BookItem,
     },
+    preprocess::PreprocessorContext,
 };
 
-#[cfg_attr(target_arch = "wasm32", plugin_helper)]
-pub fn preprocess(mut book: Book) -> Book {
+plugin_main!(preprocess);
+
+pub fn preprocess(ctx: PreprocessorContext, mut book: Book) -> Book {
+    if !enabled(&ctx) {
+        plugin_log!("wasm-to-wasm is disabled, leaving the book as is");
+        return book;
+    }
     // Iterate over the book's sections assigning
     // the updated items to the book we were passed
     book.sections = book.sections.into_iter().map(|s| {
@@ -18,7 +24,7 @@ pub fn preprocess(mut book: Book) -> Book {
             // if its a chapter, we want to update that
             BookItem::Chapter(mut ch) => {
                 // replace all WASMs with Wasms
-                ch.content = ch.content.replace("WASM", "Wasm");
+                ch.content = replace_wasm(&ch.content);
                 // Wrap the contents back up into a Chapter
                 BookItem::Chapter(ch)
             },
@@ -29,14 +35,90 @@ pub fn preprocess(mut book: Book) -> Book {
     book
 }
 
+/// The same replacement as `preprocess`, only sending
+/// back the chapters that changed
+#[plugin_helper]
+pub fn wasm_to_wasm(ctx: PreprocessorContext, book: Book) -> PluginReturn<Book, PreprocessorContext> {
+    let mut delta = Vec::new();
+    if enabled(&ctx) {
+        collect_delta(&book.sections, &mut Vec::new(), &mut delta);
+    }
+    PluginReturn {
+        delta: Some(delta),
+        ..PluginReturn::default()
+    }
+}
+
+fn collect_delta(items: &[BookItem], path: &mut ChapterPath, delta: &mut BookDelta) {
+    for (i, item) in items.iter().enumerate() {
+        if let BookItem::Chapter(ch) = item {
+            path.push(i);
+            if ch.content.contains("WASM") {
+                delta.push((path.clone(), replace_wasm(&ch.content)));
+            }
+            collect_delta(&ch.sub_items, path, delta);
+            path.pop();
+        }
+    }
+}
+
+/// The replacement on its own, a host with a single
+/// chapter can send it over without serializing anything
+#[plugin_helper(string)]
+pub fn replace_wasm(content: &str) -> String {
+    content.replace("WASM", "Wasm")
+}
+
+/// Books can opt out of the replacement by setting
+/// `wasm-to-wasm = false` in the runner's table
+fn enabled(ctx: &PreprocessorContext) -> bool {
+    ctx.config
+        .get("preprocessor.example-runner.wasm-to-wasm")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true)
+}
+
+/// Note the renderer at the end of every chapter, the
+/// context comes from `plugin_context` instead of an argument
+#[plugin_helper(plugin_context)]
+pub fn stamp_renderer(mut book: Book) -> Book {
+    let stamp = format!("<!-- built for {} -->", plugin_context().renderer);
+    book.for_each_mut(|item| if let BookItem::Chapter(ch) = item {
+        ch.content.push_str(&stamp);
+    });
+    book
+}
+
+#[plugin_helper]
+pub fn multiply(pair: (u8, String)) -> (u8, String) {
+    // Double the number and repeat the
+    // string the same number of times
+    let u = pair.0.wrapping_mul(2);
+    let s = pair.1.repeat(u as usize);
+    (u, s)
+}
+
+plugin_registry!(preprocess, wasm_to_wasm, replace_wasm, stamp_renderer, multiply);
+
 #[cfg(test)]
 mod test {
     use super::*;
     use mdbook::book::BookBuilder;
+    use serde_json::json;
+
+    fn context(config: serde_json::Value) -> PreprocessorContext {
+        serde_json::from_value(json!({
+            "root": "../../example-book",
+            "config": config,
+            "renderer": "html",
+            "mdbook_version": "0.2.3",
+        })).unwrap()
+    }
+
     #[test]
     fn check() {
         let b = BookBuilder::new("../../example-book").build().unwrap();
-        let updated = preprocess(b.book);
+        let updated = preprocess(context(json!({})), b.book);
         for section in updated.sections {
             match section { 
                 mdbook::book::BookItem::Chapter(ch) => {
@@ -46,12 +128,22 @@ mod test {
             }
         }
     }
+    #[plugin_roundtrip_test]
+    fn book_input() -> Book {
+        BookBuilder::new("../../example-book").build().unwrap().book
+    }
+
+    #[plugin_roundtrip_test]
+    fn multiply_input() -> (u8, String) {
+        (2, String::from("attributed"))
+    }
+
     #[test]
     fn ser() {
         let b = BookBuilder::new("../../example-book").build().unwrap();
         let de = revert_data(b.book);
         let s = convert_data(de.as_slice());
-        let updated = preprocess(s);
+        let updated = preprocess(context(json!({})), s);
         for section in updated.sections {
             match section { 
                 mdbook::book::BookItem::Chapter(ch) => {
@@ -61,4 +153,73 @@ mod test {
             }
         }
     }
-}
\ No newline at end of file
+    #[test]
+    fn delta() {
+        let b = BookBuilder::new("../../example-book").build().unwrap();
+        let delta = wasm_to_wasm(context(json!({})), b.book.clone()).delta.unwrap();
+        assert!(!delta.is_empty());
+        for (path, content) in delta {
+            assert!(!content.contains("WASM"));
+            let mut items = &b.book.sections;
+            let mut item = None;
+            for i in path {
+                let found = &items[i];
+                if let BookItem::Chapter(ch) = found {
+                    items = &ch.sub_items;
+                }
+                item = Some(found);
+            }
+            match item {
+                Some(BookItem::Chapter(ch)) => assert_eq!(ch.content.replace("WASM", "Wasm"), content),
+                _ => panic!("delta path doesn't point at a chapter"),
+            }
+        }
+    }
+
+    #[test]
+    fn stamps_renderer() {
+        set_plugin_context(context(json!({})));
+        let b = BookBuilder::new("../../example-book").build().unwrap();
+        let updated = stamp_renderer(b.book);
+        for item in updated.iter() {
+            if let BookItem::Chapter(ch) = item {
+                assert!(ch.content.ends_with("<!-- built for html -->"));
+            }
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let b = BookBuilder::new("../../example-book").build().unwrap();
+        let ctx = context(json!({
+            "preprocessor": {
+                "example-runner": {
+                    "wasm-to-wasm": false,
+                },
+            },
+        }));
+        let updated = preprocess(ctx, b.book);
+        let found = updated.sections.iter().any(|section| match section {
+            BookItem::Chapter(ch) => ch.content.contains("WASM"),
+            _ => false,
+        });
+        assert!(found);
+    }
+    #[cfg(feature = "messagepack")]
+    #[test]
+    fn messagepack_matches_bincode() {
+        let b = BookBuilder::new("../../example-book").build().unwrap();
+        let bincode: Book = convert_data(&revert_data(&b.book));
+        let msgpack: Book = convert_data_msgpack(&revert_data_msgpack(&b.book));
+        assert_eq!(msgpack, bincode);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_matches_bincode() {
+        let b = BookBuilder::new("../../example-book").build().unwrap();
+        let bincode: Book = convert_data(&revert_data(&b.book));
+        let cbor: Book = convert_data_cbor(&revert_data_cbor(&b.book));
+        assert_eq!(cbor, bincode);
+    }
+}
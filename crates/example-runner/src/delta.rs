@@ -0,0 +1,79 @@
+// ./crates/example-runner/src/delta.rs
+use mdbook::book::{
+    Book,
+    BookItem,
+};
+use wasmer_plugin_example::{
+    BookDelta,
+    ChapterPath,
+    PluginError,
+};
+
+/// Replace the content of each chapter in `delta`, a path
+/// that doesn't lead to a chapter is an error and leaves
+/// the book with only the edits before it applied
+pub fn apply_delta(book: &mut Book, delta: BookDelta) -> Result<(), PluginError> {
+    for (path, content) in delta {
+        let mut items = &mut book.sections;
+        let mut chapter = None;
+        for i in &path {
+            match items.get_mut(*i) {
+                Some(BookItem::Chapter(ch)) => {
+                    items = &mut ch.sub_items;
+                    chapter = Some(&mut ch.content);
+                },
+                _ => return Err(not_a_chapter(&path)),
+            }
+        }
+        *chapter.ok_or_else(|| not_a_chapter(&path))? = content;
+    }
+    Ok(())
+}
+
+fn not_a_chapter(path: &ChapterPath) -> PluginError {
+    PluginError::Plugin(format!("delta path {:?} isn't a chapter", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::book::Chapter;
+
+    fn book() -> Book {
+        let mut intro = Chapter::new("Intro", "All about WASM".to_string(), "intro.md", Vec::new());
+        intro.sub_items.push(BookItem::Chapter(Chapter::new("Why", "WASM is fast".to_string(), "why.md", Vec::new())));
+        let mut book = Book::new();
+        book.push_item(intro);
+        book.push_item(BookItem::Separator);
+        book.push_item(Chapter::new("Setup", "Install rust".to_string(), "setup.md", Vec::new()));
+        book
+    }
+
+    fn contents(book: &Book) -> Vec<String> {
+        book.iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch.content.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wasm_to_wasm() {
+        let mut book = book();
+        apply_delta(&mut book, vec![
+            (vec![0], "All about Wasm".to_string()),
+            (vec![0, 0], "Wasm is fast".to_string()),
+        ]).unwrap();
+        assert_eq!(contents(&book), vec!["All about Wasm", "Wasm is fast", "Install rust"]);
+    }
+
+    #[test]
+    fn bad_path() {
+        let mut book = book();
+        assert!(apply_delta(&mut book, vec![(vec![1], String::new())]).is_err());
+        assert!(apply_delta(&mut book, vec![(vec![5], String::new())]).is_err());
+        assert!(apply_delta(&mut book, vec![(Vec::new(), String::new())]).is_err());
+        assert_eq!(contents(&book), contents(&self::book()));
+    }
+}
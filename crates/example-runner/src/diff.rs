@@ -0,0 +1,112 @@
+// ./crates/example-runner/src/diff.rs
+use mdbook::book::{
+    Book,
+    BookItem,
+};
+use wasmer_plugin_example::ChapterPath;
+
+/// The names of the chapters whose content differs
+/// between `before` and `after`, chapters are paired
+/// up in the order they appear in the book
+pub fn changed_chapters(before: &Book, after: &Book) -> Vec<String> {
+    let chapters = |book: &Book| -> Vec<(String, String)> {
+        book.iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some((ch.name.clone(), ch.content.clone())),
+                _ => None,
+            })
+            .collect()
+    };
+    let before = chapters(before);
+    let after = chapters(after);
+    let mut changed: Vec<String> = before.iter()
+        .zip(after.iter())
+        .filter(|(b, a)| b != a)
+        .map(|(_, (name, _))| name.clone())
+        .collect();
+    // Anything added on the end counts as a change too
+    changed.extend(after.iter().skip(before.len()).map(|(name, _)| name.clone()));
+    changed
+}
+
+/// The path to every chapter in `after` that isn't in `before`
+/// or has a different name or content there, the same paths a
+/// `BookDelta` uses. Sub chapters are compared on their own so
+/// editing one doesn't mark its parent as modified
+pub fn modified_paths(before: &Book, after: &Book) -> Vec<ChapterPath> {
+    let mut modified = Vec::new();
+    collect_modified(&before.sections, &after.sections, &mut Vec::new(), &mut modified);
+    modified
+}
+
+fn collect_modified(before: &[BookItem], after: &[BookItem], path: &mut ChapterPath, modified: &mut Vec<ChapterPath>) {
+    for (i, item) in after.iter().enumerate() {
+        let ch = match item {
+            BookItem::Chapter(ch) => ch,
+            _ => continue,
+        };
+        path.push(i);
+        let old = match before.get(i) {
+            Some(BookItem::Chapter(old)) => Some(old),
+            _ => None,
+        };
+        if old.map(|old| old.name != ch.name || old.content != ch.content).unwrap_or(true) {
+            modified.push(path.clone());
+        }
+        let old_items = old.map(|old| old.sub_items.as_slice()).unwrap_or(&[]);
+        collect_modified(old_items, &ch.sub_items, path, modified);
+        path.pop();
+    }
+}
+
+/// Print what a plugin, or group of parallel
+/// plugins, changed in the book
+pub fn report(names: &str, before: &Book, after: &Book) {
+    let changed = changed_chapters(before, after);
+    println!("{}: {} chapter(s) changed", names, changed.len());
+    for name in changed {
+        println!("    {}", name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::book::Chapter;
+
+    #[test]
+    fn wasm_to_wasm() {
+        let mut before = Book::new();
+        before.push_item(Chapter::new("Intro", "All about WASM".to_string(), "intro.md", Vec::new()));
+        before.push_item(Chapter::new("Setup", "Install rust".to_string(), "setup.md", Vec::new()));
+        let mut after = before.clone();
+        for item in after.sections.iter_mut() {
+            if let BookItem::Chapter(ch) = item {
+                ch.content = ch.content.replace("WASM", "Wasm");
+            }
+        }
+        assert_eq!(changed_chapters(&before, &after), vec!["Intro"]);
+        assert!(changed_chapters(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn nested() {
+        let mut intro = Chapter::new("Intro", "All about wasm".to_string(), "intro.md", Vec::new());
+        intro.sub_items.push(BookItem::Chapter(Chapter::new("Why", "WASM is fast".to_string(), "why.md", Vec::new())));
+        let mut before = Book::new();
+        before.push_item(intro);
+        before.push_item(BookItem::Separator);
+        before.push_item(Chapter::new("Setup", "Install rust".to_string(), "setup.md", Vec::new()));
+        let mut after = before.clone();
+        if let BookItem::Chapter(intro) = &mut after.sections[0] {
+            if let BookItem::Chapter(why) = &mut intro.sub_items[0] {
+                why.content = why.content.replace("WASM", "Wasm");
+            }
+        }
+        assert_eq!(modified_paths(&before, &after), vec![vec![0, 0]]);
+        assert!(modified_paths(&before, &before).is_empty());
+        // A new chapter counts as modified
+        after.push_item(Chapter::new("Extra", String::new(), "extra.md", Vec::new()));
+        assert_eq!(modified_paths(&before, &after), vec![vec![0, 0], vec![3]]);
+    }
+}
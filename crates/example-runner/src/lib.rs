@@ -0,0 +1,2223 @@
+// ./crates/example-runner/src/lib.rs
+use flate2::read::GzDecoder;
+use log::{
+    debug,
+    error,
+    info,
+    warn,
+};
+
+mod delta;
+mod diff;
+mod manifest;
+mod metrics;
+mod output_cache;
+mod parallel;
+mod validate;
+
+use manifest::{
+    ImportPolicy,
+    PluginManifest,
+};
+use metrics::PluginMetrics;
+use output_cache::{
+    OutputCache,
+    PluginReturn,
+};
+use parallel::run_parallel;
+use serde_json::to_writer;
+use std::{
+    io::{
+        BufRead,
+        ErrorKind,
+        Read,
+        Write,
+    },
+    fs::File,
+    collections::{
+        hash_map::{
+            DefaultHasher,
+            Entry,
+        },
+        HashMap,
+    },
+    sync::Mutex,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use mdbook::{
+    book::Book,
+    preprocess::PreprocessorContext,
+};
+use wasmer_plugin_example::{
+    host_imports,
+    ChapterPath,
+    PluginError,
+    PluginHost,
+    PluginInstance,
+    SerializationFormat,
+};
+use wasmer_runtime::{
+    compile,
+    Module,
+};
+
+/// Compiled wasm modules, keyed by the file they were
+/// loaded from and a hash of that file's contents so
+/// an updated plugin is recompiled. The cache can be
+/// shared by plugins running on other threads
+#[derive(Default)]
+pub struct ModuleCache {
+    modules: Mutex<HashMap<(PathBuf, u64), Module>>,
+}
+
+impl ModuleCache {
+    /// Get the compiled module for these bytes, compiling
+    /// them if we haven't seen them before. A module is
+    /// cheap to clone so each caller gets its own copy
+    fn get_or_compile(&self, path: &Path, bytes: &[u8]) -> Result<Module, PluginError> {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        let key = (path.to_path_buf(), hasher.finish());
+        if let Some(module) = self.modules.lock().expect("Module cache lock poisoned").get(&key) {
+            return Ok(module.clone());
+        }
+        // Compiled without holding the lock so plugins
+        // running in parallel don't wait on each other
+        let module = compile(bytes)
+            .map_err(|err| PluginError::Compile(format!("{:?}, {}", path, err)))?;
+        self.modules.lock().expect("Module cache lock poisoned").insert(key, module.clone());
+        Ok(module)
+    }
+}
+
+/// The directory to look for wasm preprocessors in, this
+/// can be set with `preprocessor.wasm.dir` in book.toml
+/// and is relative to the book's root
+fn preprocessor_dir(ctx: &PreprocessorContext) -> PathBuf {
+    let dir = ctx.config.get("preprocessor.wasm.dir")
+        .and_then(|v| v.as_str())
+        .unwrap_or("preprocessors");
+    ctx.root.join(dir)
+}
+
+/// The format plugins expect their data in, this
+/// can be set with `preprocessor.wasm.format` in
+/// book.toml and defaults to bincode, or MessagePack
+/// when built with the `messagepack` feature
+fn plugin_format(ctx: &PreprocessorContext) -> Result<SerializationFormat, PluginError> {
+    ctx.config.get("preprocessor.wasm.format")
+        .and_then(|v| v.as_str())
+        .map(|s| s.parse().map_err(PluginError::Config))
+        .unwrap_or_else(|| Ok(default_format()))
+}
+
+/// The plugin function to call, this can be set with
+/// `preprocessor.wasm.entry` in book.toml so a single
+/// module can offer more than one preprocessor
+fn plugin_entry(ctx: &PreprocessorContext) -> &str {
+    ctx.config.get("preprocessor.wasm.entry")
+        .and_then(|v| v.as_str())
+        .unwrap_or("_preprocess")
+}
+
+/// If each plugin's `_health` is called before it runs, this
+/// is set with `preprocessor.wasm.health-check` in book.toml
+fn health_check(ctx: &PreprocessorContext) -> bool {
+    ctx.config.get("preprocessor.wasm.health-check")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// If the output a plugin left last time is used when it is
+/// given the same input, this is set with
+/// `preprocessor.wasm.output-cache` in book.toml
+fn output_cache(ctx: &PreprocessorContext) -> bool {
+    ctx.config.get("preprocessor.wasm.output-cache")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true)
+}
+
+/// The host every plugin is run with, its timeout can be set
+/// in milliseconds with `preprocessor.wasm.timeout` in book.toml,
+/// or `WASM_PLUGIN_FUEL` when that isn't set, and the most bytes a plugin is sent with
+/// `preprocessor.wasm.max-payload-bytes`. Anything in the
+/// `preprocessor.wasm.env` table is passed to every plugin
+/// as its environment, `preprocessor.wasm.seed` as the
+/// seed for any randomness and the strings in
+/// `preprocessor.wasm.args` as its arguments. Every plugin
+/// checks it is sent the context and book it was written for
+fn plugin_host(ctx: &PreprocessorContext) -> Result<PluginHost, PluginError> {
+    let mut builder = PluginHost::builder()
+        .with_format(plugin_format(ctx)?)
+        .with_imports(host_imports)
+        .with_type_checks(true);
+    if let Some(timeout) = ctx.config.get("preprocessor.wasm.timeout") {
+        let ms = timeout.as_integer()
+            .filter(|ms| *ms >= 0)
+            .ok_or_else(|| PluginError::Config(format!("timeout must be a number of milliseconds, found {}", timeout)))?;
+        builder = builder.with_timeout(Duration::from_millis(ms as u64));
+    } else if let Some(timeout) = fuel(std::env::var(FUEL_VAR).ok())? {
+        builder = builder.with_timeout(timeout);
+    }
+    if let Some(max) = ctx.config.get("preprocessor.wasm.max-payload-bytes") {
+        let max = max.as_integer()
+            .filter(|max| *max >= 0)
+            .ok_or_else(|| PluginError::Config(format!("max-payload-bytes must be a number of bytes, found {}", max)))?;
+        builder = builder.with_max_payload_bytes(max as usize);
+    }
+    if let Some(env) = ctx.config.get("preprocessor.wasm.env") {
+        let env = serde_json::to_value(env)
+            .map_err(|e| PluginError::Config(format!("env {}", e)))?;
+        builder = builder.with_env(env);
+    }
+    if let Some(seed) = ctx.config.get("preprocessor.wasm.seed") {
+        let seed = seed.as_integer()
+            .ok_or_else(|| PluginError::Config(format!("seed must be a number, found {}", seed)))?;
+        builder = builder.with_seed(seed as u64);
+    }
+    if let Some(args) = ctx.config.get("preprocessor.wasm.args") {
+        let args = args.as_array()
+            .and_then(|args| args.iter().map(|a| a.as_str().map(String::from)).collect::<Option<Vec<String>>>())
+            .ok_or_else(|| PluginError::Config(format!("args must be a list of strings, found {}", args)))?;
+        builder = builder.with_args(args);
+    }
+    Ok(builder.build())
+}
+
+/// The environment variable holding each plugin's budget
+/// when book.toml doesn't set a timeout
+const FUEL_VAR: &str = "WASM_PLUGIN_FUEL";
+
+/// The budget in `WASM_PLUGIN_FUEL`, wasmer-runtime 0.3 can't
+/// count a plugin's instructions so it is spent as milliseconds
+/// of wall-clock time and a plugin that runs out times out
+fn fuel(value: Option<String>) -> Result<Option<Duration>, PluginError> {
+    value.map(|value| value.parse()
+        .map(Duration::from_millis)
+        .map_err(|_| PluginError::Config(format!("{} must be a number of milliseconds, found {}", FUEL_VAR, value))))
+        .transpose()
+}
+
+#[cfg(not(feature = "messagepack"))]
+fn default_format() -> SerializationFormat {
+    SerializationFormat::Bincode
+}
+
+#[cfg(feature = "messagepack")]
+fn default_format() -> SerializationFormat {
+    SerializationFormat::MessagePack
+}
+
+/// The `.wasm` and gzipped `.wasm.gz` files in `dir`, sorted by path so the
+/// order plugins run in doesn't depend on the filesystem.
+/// Any files listed in `preprocessor.wasm.order` run first,
+/// in the order they are listed.
+///
+/// Only regular files are plugins, symlinks are skipped
+/// unless `preprocessor.wasm.follow-symlinks` is set. Sub
+/// directories are only searched when
+/// `preprocessor.wasm.recursive` is set
+fn plugin_paths(ctx: &PreprocessorContext, dir: &Path) -> Result<Vec<PathBuf>, PluginError> {
+    let flag = |key: &str| ctx.config.get(key)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let follow_symlinks = flag("preprocessor.wasm.follow-symlinks");
+    let recursive = flag("preprocessor.wasm.recursive");
+    let mut paths = Vec::new();
+    // No directory means no plugins, anything else
+    // stopping us from reading it is still an error
+    if let Err(e) = dir.metadata() {
+        if e.kind() == ErrorKind::NotFound {
+            debug!("{:?} doesn't exist, there are no wasm preprocessors to run", dir);
+            return Ok(paths);
+        }
+    }
+    collect_plugins(dir, follow_symlinks, recursive, &mut paths)?;
+    paths.sort();
+    let order: Vec<&str> = ctx.config.get("preprocessor.wasm.order")
+        .and_then(|v| v.as_array())
+        .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    // A stable sort keeps the unlisted plugins in path order
+    paths.sort_by_key(|path| {
+        let name = path.file_name().and_then(|n| n.to_str());
+        order.iter()
+            .position(|o| Some(*o) == name)
+            .unwrap_or(order.len())
+    });
+    Ok(paths)
+}
+
+fn collect_plugins(dir: &Path, follow_symlinks: bool, recursive: bool, paths: &mut Vec<PathBuf>) -> Result<(), PluginError> {
+    for entry in dir.read_dir().map_err(|e| PluginError::Io(format!("directory {:?}, {}", dir, e)))? {
+        let entry = entry.map_err(|e| PluginError::Io(format!("entry in {:?}, {}", dir, e)))?;
+        let path = entry.path();
+        debug!("{:?}", path);
+        let file_type = entry.file_type()
+            .map_err(|e| PluginError::Io(format!("{:?}, {}", path, e)))?;
+        // Symlinked directories aren't followed
+        // so we can't end up in a loop
+        if recursive && file_type.is_dir() {
+            collect_plugins(&path, follow_symlinks, recursive, paths)?;
+            continue;
+        }
+        if !path.extension().map(|ext| ext == "wasm").unwrap_or(false) && !is_gzipped(&path) {
+            continue;
+        }
+        let is_file = if file_type.is_symlink() {
+            // A broken link isn't a plugin either
+            follow_symlinks && path.metadata().map(|m| m.is_file()).unwrap_or(false)
+        } else {
+            file_type.is_file()
+        };
+        if is_file {
+            paths.push(path);
+        } else {
+            warn!("Skipping {:?}, it isn't a regular file", path);
+        }
+    }
+    Ok(())
+}
+
+/// If `path` is a gzipped plugin, these end in `.wasm.gz`
+fn is_gzipped(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.ends_with(".wasm.gz"))
+        .unwrap_or(false)
+}
+
+/// Read the module at `path`, a `.wasm.gz` is
+/// decompressed into memory as it is read
+fn read_plugin(path: &Path) -> Result<Vec<u8>, PluginError> {
+    let mut buf = Vec::new();
+    let mut f = File::open(path).map_err(|e| PluginError::Io(format!("{:?}, {}", path, e)))?;
+    let read = if is_gzipped(path) {
+        GzDecoder::new(f).read_to_end(&mut buf)
+    } else {
+        f.read_to_end(&mut buf)
+    };
+    read.map_err(|e| PluginError::Io(format!("{:?}, {}", path, e)))?;
+    Ok(buf)
+}
+
+/// A plugin's wasm and manifest, ready to run
+struct Plugin {
+    /// Where the plugin was loaded from, or the name it was
+    /// given, its errors and modified chapters use this
+    path: PathBuf,
+    wasm: Vec<u8>,
+    manifest: PluginManifest,
+}
+
+impl Plugin {
+    /// Read the plugin at `path` and its manifest
+    fn load(path: &Path) -> Result<Self, PluginError> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            wasm: read_plugin(path)?,
+            manifest: PluginManifest::load(path)?,
+        })
+    }
+
+    /// A plugin that was never on disk, the caller
+    /// handing it over trusts it with the host's imports
+    fn from_bytes(name: String, wasm: Vec<u8>) -> Self {
+        Self {
+            path: PathBuf::from(name),
+            wasm,
+            manifest: PluginManifest {
+                imports: ImportPolicy::Host,
+                ..PluginManifest::default()
+            },
+        }
+    }
+}
+
+/// The context a preprocessor would be given for the book at
+/// `root`, for commands that run outside of a build
+pub fn book_context(root: &Path) -> Result<PreprocessorContext, PluginError> {
+    let toml = root.join("book.toml");
+    let config = if toml.exists() {
+        mdbook::Config::from_disk(&toml)
+            .map_err(|e| PluginError::Config(format!("{:?}, {}", toml, e)))?
+    } else {
+        mdbook::Config::default()
+    };
+    serde_json::from_value(serde_json::json!({
+        "root": root,
+        "config": config,
+        "renderer": "html",
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    })).map_err(|e| PluginError::Config(e.to_string()))
+}
+
+/// Every plugin a run would pick up, in the order they would
+/// run, along with why it couldn't be run if it can't
+pub fn list_plugins(ctx: &PreprocessorContext) -> Result<Vec<(PathBuf, Result<(), PluginError>)>, PluginError> {
+    let host = plugin_host(ctx)?;
+    let entry = plugin_entry(ctx);
+    let dir = preprocessor_dir(ctx);
+    Ok(plugin_paths(ctx, &dir)?.into_iter()
+        .map(|path| {
+            let status = PluginManifest::load(&path)
+                .and_then(|manifest| check_plugin(&sandboxed(&host, &manifest), &path, entry));
+            (path, status)
+        })
+        .collect())
+}
+
+/// Make sure the plugin at `path` can be instantiated and
+/// exports `entry`, without running anything
+fn check_plugin(host: &PluginHost, path: &Path, entry: &str) -> Result<(), PluginError> {
+    let buf = read_plugin(path)?;
+    let module = compile(&buf)
+        .map_err(|e| PluginError::Compile(e.to_string()))?;
+    check_entry(&host.instantiate(&module)?, entry)
+}
+
+fn check_entry(instance: &PluginInstance, entry: &str) -> Result<(), PluginError> {
+    let exports = instance.exports();
+    if !exports.iter().any(|e| e == entry) {
+        return Err(PluginError::MissingExport(format!("{}, found [{}]", entry, exports.join(", "))));
+    }
+    Ok(())
+}
+
+/// Run every plugin in the plugin directory over the book, when
+/// `dry_run` is set what each one changes is printed as it runs.
+/// Once they have all run their metrics are logged. A plugin that
+/// fails is logged and skipped, unless `strict` is set in which
+/// case its error is returned and nothing after it runs.
+///
+/// A plugin marked with `plugin_helper(rerun)` can ask for another
+/// pass, then every plugin runs again over the updated book. This
+/// repeats until no plugin asks or `preprocessor.wasm.max-passes`
+/// passes, 8 by default, have run.
+///
+/// The book is checked with `validate_book` first, nothing runs
+/// over a malformed one. Along with the book this returns the
+/// paths of the chapters each plugin modified, keyed by the
+/// plugin's path, so a build can tell which chapters are the
+/// same as last time
+pub fn run_all_preprocessors(cache: &ModuleCache, ctx: &PreprocessorContext, book: Book, dry_run: bool, strict: bool) -> Result<(Book, HashMap<PathBuf, Vec<ChapterPath>>), PluginError> {
+    let dir = preprocessor_dir(ctx);
+    debug!("checking {:?} for wasm preprocessors", &dir);
+    let paths = plugin_paths(ctx, &dir).map_err(|e| {
+        error!("{}", e);
+        e
+    })?;
+    let mut plugins = Vec::new();
+    for path in paths {
+        info!("Found wasm preprocessor {:?}", path.file_name().expect("extention with no file name"));
+        match Plugin::load(&path) {
+            Ok(plugin) => plugins.push(plugin),
+            Err(e) => plugin_failed(e, strict)?,
+        }
+    }
+    run_plugins(cache, ctx, book, &plugins, &format!("{:?}", dir), dry_run, strict)
+}
+
+/// Run each named wasm module in `plugins` over the book, in
+/// order, like `run_all_preprocessors`. These have no manifest
+/// so they get the host's imports, a failure is logged and skipped
+pub fn run_preprocessors_from_bytes(cache: &ModuleCache, ctx: &PreprocessorContext, book: Book, plugins: Vec<(String, Vec<u8>)>) -> Result<(Book, HashMap<PathBuf, Vec<ChapterPath>>), PluginError> {
+    let plugins: Vec<Plugin> = plugins.into_iter()
+        .map(|(name, wasm)| Plugin::from_bytes(name, wasm))
+        .collect();
+    run_plugins(cache, ctx, book, &plugins, "the plugins given", false, false)
+}
+
+/// Run only the plugin called `name` from the plugin directory,
+/// such as `example_plugin.wasm`, returning its error if it fails
+/// and the paths of the chapters it modified along with the book
+pub fn preprocess_one(cache: &ModuleCache, ctx: &PreprocessorContext, book: Book, name: &str) -> Result<(Book, Vec<ChapterPath>), PluginError> {
+    let dir = preprocessor_dir(ctx);
+    let path = plugin_paths(ctx, &dir)?
+        .into_iter()
+        .find(|path| path.file_name().map(|n| n == name).unwrap_or(false))
+        .ok_or_else(|| PluginError::NotFound(format!("{} in {:?}", name, dir)))?;
+    let plugin = Plugin::load(&path)?;
+    let (book, mut modified) = run_plugins(cache, ctx, book, &[plugin], &format!("{:?}", path), false, true)?;
+    Ok((book, modified.remove(&path).unwrap_or_default()))
+}
+
+/// The passes over the book every run shares,
+/// `source` says where the plugins came from in
+/// what's logged
+fn run_plugins(cache: &ModuleCache, ctx: &PreprocessorContext, mut book: Book, plugins: &[Plugin], source: &str, dry_run: bool, strict: bool) -> Result<(Book, HashMap<PathBuf, Vec<ChapterPath>>), PluginError> {
+    // Catch a bad book before any plugin runs
+    validate::validate_book(&book)?;
+    let host = plugin_host(ctx)?;
+    let mut metrics = Vec::new();
+    let mut modified = HashMap::new();
+    let mut instances = HashMap::new();
+    let max_passes = max_passes(ctx)?;
+    let mut pass = 0;
+    let mut rerun = true;
+    while rerun {
+        if pass == max_passes {
+            warn!("a plugin in {} asked for another pass after {} passes, stopping", source, max_passes);
+            break;
+        }
+        pass += 1;
+        rerun = false;
+        debug!("pass {} over {}", pass, source);
+        // Each plugin sees the context left by the one before it
+        let mut ctx = ctx.clone();
+        // Plugins that can run in parallel are held on to
+        // until we reach one that can't
+        let mut parallel = Vec::new();
+        for plugin in plugins {
+            let plugin_host = sandboxed(&host, &plugin.manifest);
+            if plugin.manifest.parallel {
+                parallel.push((plugin, plugin_host));
+                continue;
+            }
+            let (updated, parallel_rerun) = run_all_parallel(cache, &mut parallel, &ctx, book, dry_run, strict, &mut metrics, &mut modified)?;
+            book = updated;
+            rerun |= parallel_rerun;
+            let path = &plugin.path;
+            // A failing plugin shouldn't throw away the work
+            // of the others, so unless we're strict we log it
+            // and move on with the book as it was
+            match run_preprocessor(cache, &mut instances, &plugin_host, plugin, &ctx, &book) {
+                Ok(run) => {
+                    if dry_run {
+                        diff::report(&format!("{:?}", path), &book, &run.book);
+                    }
+                    report_warnings(path, &run.warnings);
+                    if let Err(e) = write_files(&ctx, &run.files, dry_run) {
+                        plugin_failed(e.named(&format!("{:?}", path)), strict)?;
+                    }
+                    book = run.book;
+                    if let Some(updated_ctx) = run.context {
+                        ctx = updated_ctx;
+                    }
+                    rerun |= run.rerun;
+                    record_modified(&mut modified, path.clone(), run.modified);
+                    metrics.push(run.metrics);
+                },
+                Err(e) => {
+                    // A plugin that trapped may have been
+                    // left half way through changing its memory
+                    instances.remove(path);
+                    plugin_failed(e, strict)?;
+                },
+            }
+            // The next plugin, and this one in the next pass,
+            // shouldn't be able to read what it was sent
+            if let Some(instance) = instances.get(path) {
+                instance.reset_memory();
+            }
+        }
+        let (updated, parallel_rerun) = run_all_parallel(cache, &mut parallel, &ctx, book, dry_run, strict, &mut metrics, &mut modified)?;
+        book = updated;
+        rerun |= parallel_rerun;
+    }
+    metrics::report(&metrics);
+    Ok((book, modified))
+}
+
+/// The most passes over the book a run can take, this can be set
+/// with `preprocessor.wasm.max-passes` in book.toml and is there
+/// so plugins that keep asking for another pass can't loop forever
+fn max_passes(ctx: &PreprocessorContext) -> Result<usize, PluginError> {
+    match ctx.config.get("preprocessor.wasm.max-passes") {
+        Some(max) => max.as_integer()
+            .filter(|max| *max > 0)
+            .map(|max| max as usize)
+            .ok_or_else(|| PluginError::Config(format!("max-passes must be a number of passes above 0, found {}", max))),
+        None => Ok(DEFAULT_MAX_PASSES),
+    }
+}
+
+/// The passes a run can take when book.toml doesn't say
+const DEFAULT_MAX_PASSES: usize = 8;
+
+/// Add the chapters a plugin modified to the ones it
+/// modified in earlier passes
+fn record_modified(modified: &mut HashMap<PathBuf, Vec<ChapterPath>>, path: PathBuf, chapters: Vec<ChapterPath>) {
+    let all = modified.entry(path).or_insert_with(Vec::new);
+    all.extend(chapters);
+    all.sort();
+    all.dedup();
+}
+
+/// Pass the arguments from the command line on to the plugins as
+/// `preprocessor.wasm.args`, replacing any set in book.toml
+pub fn forward_args(ctx: &mut PreprocessorContext, args: &[String]) -> Result<(), PluginError> {
+    if args.is_empty() {
+        return Ok(());
+    }
+    ctx.config.set("preprocessor.wasm.args", args)
+        .map_err(|e| PluginError::Config(format!("args {}", e)))
+}
+
+/// Send plugins data in `format` from the command line,
+/// replacing `preprocessor.wasm.format` from book.toml
+pub fn override_format(ctx: &mut PreprocessorContext, format: Option<&str>) -> Result<(), PluginError> {
+    let format = match format {
+        Some(format) => format,
+        None => return Ok(()),
+    };
+    // Fail here instead of when the first plugin runs
+    format.parse::<SerializationFormat>()
+        .map_err(PluginError::Config)?;
+    ctx.config.set("preprocessor.wasm.format", format)
+        .map_err(|e| PluginError::Config(format!("format {}", e)))
+}
+
+/// Log each warning the plugin at `path`
+/// returned, prefixed with its name
+fn report_warnings(path: &Path, warnings: &[String]) {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    for warning in warnings {
+        warn!("{}: {}", name, warning);
+    }
+}
+
+/// Log a plugin's error and carry on, or
+/// with `strict` fail the whole run with it
+fn plugin_failed(e: PluginError, strict: bool) -> Result<(), PluginError> {
+    if strict {
+        return Err(e);
+    }
+    error!("{}", e);
+    Ok(())
+}
+
+/// Run the plugins over every book in `input`, each line is the
+/// JSON for a context and book like mdBook would send. The updated
+/// books are written to `output` one per line, in the order they
+/// were read. Every book shares the same module cache so a plugin
+/// used by more than one is only compiled once
+pub fn run_batch<R: BufRead, W: Write>(input: R, mut output: W, args: &[String], format: Option<&str>, dry_run: bool, strict: bool) -> Result<(), PluginError> {
+    let cache = ModuleCache::default();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| PluginError::Io(format!("line {} {}", i + 1, e)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (mut ctx, book): (PreprocessorContext, Book) = serde_json::from_str(&line)
+            .map_err(|e| PluginError::Serde(format!("line {} {}", i + 1, e)))?;
+        forward_args(&mut ctx, args)?;
+        override_format(&mut ctx, format)?;
+        let (updated, _) = run_all_preprocessors(&cache, &ctx, book, dry_run, strict)?;
+        if dry_run {
+            continue;
+        }
+        to_writer(&mut output, &updated)
+            .map_err(|e| PluginError::Serde(e.to_string()))?;
+        writeln!(output)
+            .map_err(|e| PluginError::Io(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Write the files a plugin returned under the book's src,
+/// creating any directories they need. A dry run only prints
+/// where each would have gone
+fn write_files(ctx: &PreprocessorContext, files: &[(PathBuf, String)], dry_run: bool) -> Result<(), PluginError> {
+    let src = ctx.root.join(&ctx.config.book.src);
+    for (path, content) in files {
+        let dest = src.join(path);
+        if dry_run {
+            println!("    would write {:?}", dest);
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| PluginError::Io(format!("{:?} {}", parent, e)))?;
+        }
+        std::fs::write(&dest, content)
+            .map_err(|e| PluginError::Io(format!("{:?} {}", dest, e)))?;
+    }
+    Ok(())
+}
+
+/// The host for a plugin with `manifest`, the same as `host`
+/// but only giving the plugin the imports its policy allows
+fn sandboxed(host: &PluginHost, manifest: &PluginManifest) -> PluginHost {
+    host.with_imports(manifest.imports.imports())
+}
+
+/// Run and clear out the plugins waiting to run in parallel, each
+/// with its own host, adding the metrics and modified chapters for
+/// each one that succeeded to `metrics` and `modified`. The chapters
+/// are the ones a plugin modified in its own copy of the book. With
+/// `strict` the first plugin to fail fails them all. Along with the
+/// book this returns if any of them asked for another pass
+fn run_all_parallel(cache: &ModuleCache, plugins: &mut Vec<(&Plugin, PluginHost)>, ctx: &PreprocessorContext, book: Book, dry_run: bool, strict: bool, metrics: &mut Vec<PluginMetrics>, modified: &mut HashMap<PathBuf, Vec<ChapterPath>>) -> Result<(Book, bool), PluginError> {
+    if plugins.is_empty() {
+        return Ok((book, false));
+    }
+    let paths: Vec<PathBuf> = plugins.iter().map(|(plugin, _)| plugin.path.clone()).collect();
+    let before = if dry_run { Some(book.clone()) } else { None };
+    let collected = Mutex::new(Vec::new());
+    let failed = Mutex::new(Vec::new());
+    let updated = run_parallel(&paths, book, |path, book| {
+        let (plugin, host) = plugins.iter()
+            .find(|(plugin, _)| plugin.path == path)
+            .expect("parallel plugin without a host");
+        // They all start from the same context, so any
+        // changes they make to it are dropped. Each runs
+        // on its own thread so none keep their instance
+        let run = run_preprocessor(cache, &mut HashMap::new(), host, plugin, ctx, book)
+            .map_err(|e| {
+                failed.lock().expect("Failures lock poisoned").push(e.clone());
+                e
+            })?;
+        report_warnings(path, &run.warnings);
+        collected.lock().expect("Metrics lock poisoned").push((path.to_path_buf(), run.modified, run.files, run.rerun, run.metrics));
+        Ok(run.book)
+    });
+    plugins.clear();
+    if strict {
+        if let Some(e) = failed.into_inner().expect("Failures lock poisoned").into_iter().next() {
+            return Err(e);
+        }
+    }
+    let mut rerun = false;
+    for (path, plugin_modified, files, plugin_rerun, plugin_metrics) in collected.into_inner().expect("Metrics lock poisoned") {
+        if let Err(e) = write_files(ctx, &files, dry_run) {
+            plugin_failed(e.named(&format!("{:?}", path)), strict)?;
+        }
+        rerun |= plugin_rerun;
+        record_modified(modified, path, plugin_modified);
+        metrics.push(plugin_metrics);
+    }
+    if let Some(before) = before {
+        diff::report(&format!("{:?}", paths), &before, &updated);
+    }
+    Ok((updated, rerun))
+}
+
+/// What running a single plugin left behind
+struct PluginRun {
+    /// The updated book
+    book: Book,
+    /// The context the plugins after it should see,
+    /// when the plugin returned one
+    context: Option<PreprocessorContext>,
+    /// The paths of the chapters it modified
+    modified: Vec<ChapterPath>,
+    /// New files to write to the book's src, relative to it
+    files: Vec<(PathBuf, String)>,
+    /// Anything the plugin warned about, which
+    /// didn't stop it from updating the book
+    warnings: Vec<String>,
+    /// The plugin asked for another pass over the book
+    rerun: bool,
+    metrics: PluginMetrics,
+}
+
+impl PluginRun {
+    /// The run of a plugin that was handed `before` and gave
+    /// back `returned`, without a book `before` is kept and
+    /// any delta is applied to it. Nothing is written unless
+    /// every file stays in the book
+    fn new(before: &Book, returned: PluginReturn, metrics: PluginMetrics) -> Result<Self, PluginError> {
+        for (path, _) in &returned.files {
+            validate::validate_file_path(path)?;
+        }
+        let mut book = returned.book.unwrap_or_else(|| before.clone());
+        if let Some(delta) = returned.delta {
+            delta::apply_delta(&mut book, delta)?;
+        }
+        Ok(Self {
+            modified: diff::modified_paths(before, &book),
+            book,
+            context: returned.context,
+            files: returned.files,
+            warnings: returned.warnings,
+            rerun: returned.rerun,
+            metrics,
+        })
+    }
+}
+
+/// Compile and run `plugin`, any error will include
+/// its path. When this plugin has already run over the
+/// same context and book the output it left in the
+/// output cache is used instead, unless book.toml
+/// turns the cache off. The plugin's instance is kept
+/// in `instances` for the next pass to reuse
+fn run_preprocessor(cache: &ModuleCache, instances: &mut HashMap<PathBuf, PluginInstance>, host: &PluginHost, plugin: &Plugin, ctx: &PreprocessorContext, book: &Book) -> Result<PluginRun, PluginError> {
+    let (path, buf) = (&plugin.path, &plugin.wasm);
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let outputs = OutputCache::new(ctx);
+    let key = if output_cache(ctx) {
+        Some(OutputCache::key(buf, ctx, book)
+            .map_err(|e| e.named(&format!("{:?}", path)))?)
+    } else {
+        None
+    };
+    if let Some(cached) = key.and_then(|key| outputs.get(key)) {
+        return PluginRun::new(book, cached, PluginMetrics {
+            name,
+            cached: true,
+            ..PluginMetrics::default()
+        }).map_err(|e| e.named(&format!("{:?}", path)));
+    }
+    let start = Instant::now();
+    let ret = if host.timeout().is_some() {
+        // The plugin runs on its own thread, which needs its
+        // own copy of everything, so it isn't kept in `instances`
+        let (thread_host, ctx, book, thread_name) = (host.clone(), ctx.clone(), book.clone(), name.clone());
+        cache.get_or_compile(path, buf).and_then(|module| host.run(&name, move || {
+            let instance = thread_host.instantiate(&module)?.with_log_name(&thread_name);
+            let load = start.elapsed();
+            preprocess(&instance, &thread_name, &ctx, &book)
+                .map(|run| PluginRun { metrics: PluginMetrics { load, ..run.metrics }, ..run })
+        }))
+    } else {
+        // A plugin that already ran in an earlier pass
+        // reuses the instance it ran with
+        let instance = match instances.entry(path.clone()) {
+            Entry::Occupied(e) => Ok(e.into_mut()),
+            Entry::Vacant(e) => cache.get_or_compile(path, buf)
+                .and_then(|module| host.instantiate(&module))
+                .map(|instance| e.insert(instance.with_log_name(&name))),
+        };
+        instance.and_then(|instance| {
+            let load = start.elapsed();
+            preprocess(instance, &name, ctx, book)
+                .map(|run| PluginRun { metrics: PluginMetrics { load, ..run.metrics }, ..run })
+        })
+    };
+    let run = ret.map_err(|e| e.named(&format!("{:?}", path)))?;
+    // Not being able to save the output only
+    // costs the next build a call
+    if let Some(key) = key {
+        let output = PluginReturn {
+            book: Some(run.book.clone()),
+            delta: None,
+            context: run.context.clone(),
+            files: run.files.clone(),
+            warnings: run.warnings.clone(),
+            rerun: run.rerun,
+        };
+        if let Err(e) = outputs.put(key, &output) {
+            warn!("{}", e.named(&format!("{:?}", path)));
+        }
+    }
+    Ok(run)
+}
+
+/// Run a single wasm preprocessor, passing it both
+/// the context and the book, errors are reported with
+/// `name`. A plugin marked with `plugin_helper(context)`
+/// also hands back the context the plugins after it
+/// should see.
+///
+/// The metrics returned only cover calling the plugin,
+/// their `load` time is left for the caller to fill in
+fn preprocess(instance: &PluginInstance, name: &str, ctx: &PreprocessorContext, book: &Book) -> Result<PluginRun, PluginError> {
+    let start = Instant::now();
+    // A reused instance counts every call it has made
+    let (before_in, before_out) = instance.bytes_marshalled();
+    let metrics = |instance: &PluginInstance| {
+        let (bytes_in, bytes_out) = instance.bytes_marshalled();
+        PluginMetrics {
+            name: name.to_string(),
+            call: start.elapsed(),
+            bytes_in: bytes_in - before_in,
+            bytes_out: bytes_out - before_out,
+            ..PluginMetrics::default()
+        }
+    };
+    // Nothing is marshalled for a plugin
+    // that lays out memory differently
+    instance.check_abi()?;
+    if health_check(ctx) && !instance.healthy()? {
+        return Err(PluginError::Unhealthy(format!("{} reported it isn't healthy", name)));
+    }
+    if !instance.supports(&ctx.renderer)? {
+        info!("{} doesn't support the {} renderer, skipping it", name, ctx.renderer);
+        return PluginRun::new(book, PluginReturn::default(), metrics(instance));
+    }
+    let entry = plugin_entry(ctx);
+    check_entry(instance, entry)?;
+    // A plugin with no output leaves the book as it was, one
+    // returning a `PluginReturn` can send anything along with
+    // its book. They are sent owned so the type matches the
+    // `(PreprocessorContext, Book)` the plugin reads
+    let input = (ctx.clone(), book.clone());
+    let updated = if instance.returns_plugin_return(entry) {
+        instance.call_optional(entry, input)
+    } else {
+        instance.call_optional(entry, input)
+            .map(|ret| ret.map(PluginReturn::new))
+    };
+    // A panic traps, the message it left is more useful
+    // than wasmer's description of the trap
+    let updated = updated.map_err(|e| match (e, instance.panic_message()) {
+        (PluginError::Trap(_), Some(msg)) => PluginError::Panic(msg),
+        (e, _) => e,
+    });
+    let returned = updated?.unwrap_or_default();
+    PluginRun::new(book, returned, metrics(instance))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::{
+        Level,
+        LevelFilter,
+        Log,
+        Metadata,
+        Record,
+    };
+    use mdbook::book::{
+        BookItem,
+        Chapter,
+    };
+    use std::sync::Once;
+
+    /// Keeps every message logged by any test, tests share the
+    /// one logger so they need to look for their own messages
+    struct CaptureLogger;
+
+    static LOGGED: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+    static CAPTURE: CaptureLogger = CaptureLogger;
+    static INSTALL_LOGGER: Once = Once::new();
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LOGGED.lock().expect("Log lock poisoned").push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Start capturing log messages if no test has yet
+    fn capture_logs() {
+        INSTALL_LOGGER.call_once(|| {
+            log::set_logger(&CAPTURE).expect("Logger already set");
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    /// Everything logged since `capture_logs` was first called
+    fn logged() -> Vec<(Level, String)> {
+        LOGGED.lock().expect("Log lock poisoned").clone()
+    }
+
+    #[test]
+    fn cache_reuses_modules() {
+        let cache = ModuleCache::default();
+        let path = Path::new("preprocessors/empty.wasm");
+        let empty = wabt::wat2wasm("(module)").unwrap();
+        cache.get_or_compile(path, &empty).unwrap();
+        cache.get_or_compile(path, &empty).unwrap();
+        assert_eq!(cache.modules.lock().unwrap().len(), 1);
+        // Changing the contents should compile again
+        let other = wabt::wat2wasm("(module (memory 1))").unwrap();
+        cache.get_or_compile(path, &other).unwrap();
+        assert_eq!(cache.modules.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn cache_shared_with_timeout() {
+        let fx = Fixture::new("cache-shared-with-timeout", serde_json::json!({ "output-cache": false }));
+        let plugin = Plugin::load(&fx.plugin("a.wasm", &constant_plugin("_preprocess", Book::new(), None))).unwrap();
+        // Runs the plugin on its own thread
+        let host = PluginHost::builder()
+            .with_format(SerializationFormat::Json)
+            .with_timeout(Duration::from_secs(10))
+            .build();
+        run_preprocessor(&fx.cache, &mut HashMap::new(), &host, &plugin, &fx.ctx, &Book::new()).unwrap();
+        run_preprocessor(&fx.cache, &mut HashMap::new(), &host, &plugin, &fx.ctx, &Book::new()).unwrap();
+        assert_eq!(fx.cache.modules.lock().unwrap().len(), 1);
+    }
+
+    // Run with `cargo test --release -p mdbook-example-runner -- --ignored three_plugins --nocapture`
+    #[test]
+    #[ignore]
+    fn three_plugins() {
+        let root = test_dir("three-plugins");
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "format": "json",
+                    "timeout": 10_000,
+                    "output-cache": false,
+                },
+            },
+        }));
+        let plugins: Vec<(String, Vec<u8>)> = ["a.wasm", "b.wasm", "c.wasm"].iter()
+            .map(|name| (name.to_string(), constant_plugin("_preprocess", Book::new(), None)))
+            .collect();
+        // Compiling every plugin for every book, like before
+        // the cache, and compiling them once for all of them
+        let start = Instant::now();
+        for _ in 0..50 {
+            run_preprocessors_from_bytes(&ModuleCache::default(), &ctx, Book::new(), plugins.clone()).unwrap();
+        }
+        let fresh = start.elapsed();
+        let cache = ModuleCache::default();
+        let start = Instant::now();
+        for _ in 0..50 {
+            run_preprocessors_from_bytes(&cache, &ctx, Book::new(), plugins.clone()).unwrap();
+        }
+        let cached = start.elapsed();
+        println!("fresh {:?}, cached {:?}", fresh, cached);
+        assert!(cached < fresh);
+    }
+
+    // Run with `cargo test --release -p mdbook-example-runner -- --ignored cell_copy --nocapture`
+    // and again with `--features compress` to compare
+    #[test]
+    #[ignore]
+    fn cell_copy() {
+        use wasmer_plugin_example::{read_output, write_input, MemoryLayout};
+        use wasmer_runtime::{
+            types::MemoryDescriptor,
+            units::Pages,
+            Memory,
+        };
+        let root = test_dir("cell-copy");
+        let ctx = context(&root, serde_json::json!({}));
+        // The example book a hundred times over
+        let example = mdbook::MDBook::load("../../example-book").unwrap().book;
+        let mut book = Book::new();
+        for _ in 0..100 {
+            book.sections.extend(example.sections.iter().cloned());
+        }
+        let memory = Memory::new(MemoryDescriptor {
+            minimum: Pages(1),
+            maximum: None,
+            shared: false,
+        }).unwrap();
+        let start = Instant::now();
+        let mut len = 0;
+        for _ in 0..10 {
+            // Both ways across the boundary, including
+            // the time it takes to compress
+            let bytes = SerializationFormat::Bincode.serialize((&ctx, &book)).unwrap();
+            len = bytes.len();
+            write_input(&memory, MemoryLayout::data_offset() as i32, &bytes).unwrap();
+            MemoryLayout::write_len(&memory.view::<u8>(), len as u32);
+            let back = read_output(&memory, MemoryLayout::data_offset() as i32).unwrap().unwrap();
+            let _: (PreprocessorContext, Book) = SerializationFormat::Bincode.deserialize_owned(&back).unwrap();
+        }
+        println!("compress {}, {} bytes, {:?}", cfg!(feature = "compress"), len, start.elapsed());
+    }
+
+    fn instance(module: &Module) -> PluginInstance {
+        PluginHost::default().instantiate(module).unwrap()
+    }
+
+    fn context(root: &Path, config: serde_json::Value) -> PreprocessorContext {
+        serde_json::from_value(serde_json::json!({
+            "root": root,
+            "config": config,
+            "renderer": "html",
+            "mdbook_version": "0.2.3",
+        })).unwrap()
+    }
+
+    /// Create an empty directory for a test to work in
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("example-runner-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("preprocessors")).unwrap();
+        dir
+    }
+
+    /// A book in its own `test_dir` for plugins to run against,
+    /// the context reads JSON unless `wasm` says otherwise
+    struct Fixture {
+        root: PathBuf,
+        ctx: PreprocessorContext,
+        cache: ModuleCache,
+    }
+
+    impl Fixture {
+        /// `wasm` is added to the `[preprocessor.wasm]` table
+        fn new(name: &str, wasm: serde_json::Value) -> Self {
+            let root = test_dir(name);
+            let mut config = serde_json::json!({ "format": "json" });
+            if let serde_json::Value::Object(settings) = wasm {
+                config.as_object_mut().unwrap().extend(settings);
+            }
+            let ctx = context(&root, serde_json::json!({ "preprocessor": { "wasm": config } }));
+            Fixture { root, ctx, cache: ModuleCache::default() }
+        }
+
+        /// Write `wasm` to the plugin directory as `name`
+        fn plugin(&self, name: &str, wasm: &[u8]) -> PathBuf {
+            let path = self.root.join("preprocessors").join(name);
+            std::fs::write(&path, wasm).unwrap();
+            path
+        }
+
+        /// Run the plugin at `path` by itself
+        fn run(&self, path: &Path, book: &Book) -> Result<PluginRun, PluginError> {
+            let host = plugin_host(&self.ctx).unwrap();
+            run_preprocessor(&self.cache, &mut HashMap::new(), &host, &Plugin::load(path).unwrap(), &self.ctx, book)
+        }
+
+        /// Run every plugin the way `mdbook` would
+        fn run_all(&self, book: Book, strict: bool) -> Result<(Book, HashMap<PathBuf, Vec<ChapterPath>>), PluginError> {
+            run_all_preprocessors(&self.cache, &self.ctx, book, false, strict)
+        }
+    }
+
+    #[test]
+    fn invalid_module() {
+        capture_logs();
+        let fx = Fixture::new("invalid-module", serde_json::json!({}));
+        let path = fx.plugin("bad.wasm", b"not a wasm module");
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::Compile(msg)) => assert!(msg.contains("bad.wasm")),
+            other => panic!("expected a compile error, found {:?}", other.map(|run| run.book)),
+        }
+        // The rest of the run should carry on without it,
+        // logging why it was skipped as an error
+        assert!(fx.run_all(Book::new(), false).is_ok());
+        assert!(logged().iter().any(|(level, msg)| *level == Level::Error && msg.contains("bad.wasm")));
+    }
+
+    #[test]
+    fn skips_directories() {
+        let root = test_dir("skips-directories");
+        let dir = root.join("preprocessors");
+        std::fs::create_dir(dir.join("bar.wasm")).unwrap();
+        std::fs::write(dir.join("foo.wasm"), b"").unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(plugin_paths(&ctx, &dir).unwrap(), vec![dir.join("foo.wasm")]);
+    }
+
+    #[test]
+    fn recursive() {
+        let root = test_dir("recursive");
+        let dir = root.join("preprocessors");
+        std::fs::create_dir_all(dir.join("text").join("spelling")).unwrap();
+        std::fs::write(dir.join("top.wasm"), b"").unwrap();
+        std::fs::write(dir.join("text").join("case.wasm"), b"").unwrap();
+        std::fs::write(dir.join("text").join("spelling").join("en.wasm"), b"").unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(plugin_paths(&ctx, &dir).unwrap(), vec![dir.join("top.wasm")]);
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "recursive": true,
+                },
+            },
+        }));
+        assert_eq!(plugin_paths(&ctx, &dir).unwrap(), vec![
+            dir.join("text").join("case.wasm"),
+            dir.join("text").join("spelling").join("en.wasm"),
+            dir.join("top.wasm"),
+        ]);
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzipped() {
+        let root = test_dir("gzipped");
+        let dir = root.join("preprocessors");
+        let wasm = wabt::wat2wasm("(module (memory 1))").unwrap();
+        std::fs::write(dir.join("foo.wasm.gz"), gzip(&wasm)).unwrap();
+        std::fs::write(dir.join("bar.wasm"), &wasm).unwrap();
+        std::fs::write(dir.join("notes.gz"), gzip(b"not a plugin")).unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        let paths = plugin_paths(&ctx, &dir).unwrap();
+        assert_eq!(paths, vec![dir.join("bar.wasm"), dir.join("foo.wasm.gz")]);
+        for path in paths {
+            assert_eq!(read_plugin(&path).unwrap(), wasm);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks() {
+        let root = test_dir("symlinks");
+        let dir = root.join("preprocessors");
+        std::fs::write(root.join("foo.wasm"), b"").unwrap();
+        std::os::unix::fs::symlink(root.join("foo.wasm"), dir.join("foo.wasm")).unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        assert!(plugin_paths(&ctx, &dir).unwrap().is_empty());
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "follow-symlinks": true,
+                },
+            },
+        }));
+        assert_eq!(plugin_paths(&ctx, &dir).unwrap(), vec![dir.join("foo.wasm")]);
+    }
+
+    #[test]
+    fn trap_reason() {
+        let fx = Fixture::new("trap-reason", serde_json::json!({}));
+        let path = fx.plugin("trap.wasm", &wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    unreachable))
+        "#).unwrap());
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::Trap(msg)) => {
+                assert!(msg.contains("trap.wasm"), "no file name in {:?}", msg);
+                assert!(msg.contains("in _preprocess"), "no export in {:?}", msg);
+                assert!(msg.contains("unreachable"), "no reason in {:?}", msg);
+            },
+            other => panic!("expected a trap, found {:?}", other.map(|run| run.book)),
+        }
+    }
+
+    /// A plugin whose export `entry` ignores its input and always hands
+    /// back `output` as JSON, with the marker export `marker`, such as
+    /// `__plugin_return`, for `entry` if there is one
+    fn constant_plugin<T: serde::Serialize>(entry: &str, output: T, marker: Option<&str>) -> Vec<u8> {
+        let marker = marker
+            .map(|marker| format!(r#"(func (export "{}{}"))"#, marker, entry))
+            .unwrap_or_default();
+        constant_plugin_with(entry, output, &marker)
+    }
+
+    /// The same as `constant_plugin` with the functions in `extra`
+    /// added to the module instead of a marker
+    fn constant_plugin_with<T: serde::Serialize>(entry: &str, output: T, extra: &str) -> Vec<u8> {
+        let bytes = SerializationFormat::Json.serialize(Ok::<_, String>(output)).unwrap();
+        raw_plugin(entry, &bytes, extra)
+    }
+
+    /// A plugin whose `entry` always hands back `bytes` as they are
+    fn raw_plugin(entry: &str, bytes: &[u8], extra: &str) -> Vec<u8> {
+        let data: String = bytes.iter().map(|b| format!("\\{:02x}", b)).collect();
+        wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (data (i32.const 32768) "{data}")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                {extra}
+                (func (export "{entry}") (param i32 i32) (result i32)
+                    i32.const 0
+                    i32.const {len}
+                    i32.store
+                    i32.const 32768))
+        "#, data = data, extra = extra, entry = entry, len = bytes.len())).unwrap()
+    }
+
+    #[test]
+    fn context_threads() {
+        let fx = Fixture::new("context-threads", serde_json::json!({}));
+        // The first plugin points the runner at `_second`, which
+        // is the only thing the plugin after it exports
+        let updated = context(&fx.root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "format": "json",
+                    "entry": "_second",
+                },
+            },
+        }));
+        let first = constant_plugin("_preprocess", PluginReturn {
+            context: Some(updated),
+            ..PluginReturn::default()
+        }, Some("__plugin_return"));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Second", "Ran with the new entry".to_string(), "second.md", Vec::new()));
+        let a = fx.plugin("a.wasm", &first);
+        let b = fx.plugin("b.wasm", &constant_plugin("_second", &book, None));
+        let (ran, modified) = fx.run_all(Book::new(), false).unwrap();
+        assert_eq!(ran, book);
+        assert_eq!(modified[&a], Vec::<ChapterPath>::new());
+        assert_eq!(modified[&b], vec![vec![0]]);
+    }
+
+    #[test]
+    fn delta_with_warnings() {
+        let fx = Fixture::new("delta-with-warnings", serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "Untouched".to_string(), "part_2.md", Vec::new()));
+        // Only sends the chapter it changed, along with a warning
+        let path = fx.plugin("a.wasm", &constant_plugin("_preprocess", PluginReturn {
+            delta: Some(vec![(vec![0], "Wasm".to_string())]),
+            warnings: vec!["WASM is spelled Wasm".to_string()],
+            ..PluginReturn::default()
+        }, Some("__plugin_return")));
+        let run = fx.run(&path, &book).unwrap();
+        let mut expected = book.clone();
+        if let BookItem::Chapter(ch) = &mut expected.sections[0] {
+            ch.content = "Wasm".to_string();
+        }
+        assert_eq!(run.book, expected);
+        assert_eq!(run.modified, vec![vec![0]]);
+        assert_eq!(run.warnings, vec!["WASM is spelled Wasm".to_string()]);
+    }
+
+    #[test]
+    fn writes_files() {
+        let fx = Fixture::new("writes-files", serde_json::json!({}));
+        let files = vec![(PathBuf::from("api/index.md"), "# API".to_string())];
+        fx.plugin("a.wasm", &constant_plugin("_preprocess", PluginReturn { files, ..PluginReturn::default() }, Some("__plugin_return")));
+        fx.run_all(Book::new(), false).unwrap();
+        let written = std::fs::read_to_string(fx.root.join("src").join("api").join("index.md")).unwrap();
+        assert_eq!(written, "# API");
+    }
+
+    #[test]
+    fn files_outside_of_book() {
+        let fx = Fixture::new("files-outside-of-book", serde_json::json!({}));
+        let files = vec![(PathBuf::from("../escaped.md"), String::new())];
+        let path = fx.plugin("a.wasm", &constant_plugin("_preprocess", PluginReturn { files, ..PluginReturn::default() }, Some("__plugin_return")));
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::OutsideBook(_)) => (),
+            other => panic!("expected a file outside of the book, found {:?}", other.map(|run| run.files)),
+        }
+        assert!(!fx.root.join("escaped.md").exists());
+    }
+
+    #[test]
+    fn type_checks() {
+        let fx = Fixture::new("type-checks", serde_json::json!({}));
+        let expects = |name: &str| format!(r#"
+            (data (i32.const 2048) "{}")
+            (func (export "__input_type_of_preprocess") (result i32)
+                i32.const 0
+                i32.const {}
+                i32.store
+                i32.const 2048)
+        "#, name, name.len());
+        let path = fx.plugin("a.wasm", &constant_plugin_with("_preprocess", Book::new(), &expects("(PreprocessorContext,Book)")));
+        assert!(fx.run(&path, &Book::new()).is_ok());
+        fx.plugin("a.wasm", &constant_plugin_with("_preprocess", Book::new(), &expects("Book")));
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::TypeMismatch(_)) => (),
+            other => panic!("expected a type mismatch, found {:?}", other.map(|run| run.book)),
+        }
+    }
+
+    #[test]
+    fn cached_output() {
+        let fx = Fixture::new("cached-output", serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Cached", "From the first run".to_string(), "cached.md", Vec::new()));
+        let path = fx.plugin("a.wasm", &constant_plugin("_preprocess", &book, None));
+        let first = fx.run(&path, &Book::new()).unwrap();
+        assert!(!first.metrics.cached);
+        let second = fx.run(&path, &Book::new()).unwrap();
+        assert!(second.metrics.cached);
+        assert_eq!(second.book, book);
+        assert_eq!(second.modified, vec![vec![0]]);
+        // A different book is a miss
+        let third = fx.run(&path, &book).unwrap();
+        assert!(!third.metrics.cached);
+        // And so is a different plugin
+        fx.plugin("a.wasm", &constant_plugin("_preprocess", Book::new(), None));
+        let fourth = fx.run(&path, &Book::new()).unwrap();
+        assert!(!fourth.metrics.cached);
+        assert_eq!(fourth.book, Book::new());
+    }
+
+    #[test]
+    fn cached_output_is_validated() {
+        let fx = Fixture::new("cached-output-validated", serde_json::json!({}));
+        let path = fx.plugin("a.wasm", &constant_plugin("_preprocess", Book::new(), None));
+        // A cache entry that was tampered with, or left by
+        // a runner that didn't check the files it returned
+        let key = OutputCache::key(&Plugin::load(&path).unwrap().wasm, &fx.ctx, &Book::new()).unwrap();
+        let output = PluginReturn {
+            files: vec![(PathBuf::from("../outside.md"), String::new())],
+            ..PluginReturn::new(Book::new())
+        };
+        OutputCache::new(&fx.ctx).put(key, &output).unwrap();
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::OutsideBook(_)) => (),
+            other => panic!("expected the cached file to be rejected, found {:?}", other.map(|run| run.files)),
+        }
+    }
+
+    #[test]
+    fn output_cache_off() {
+        let fx = Fixture::new("output-cache-off", serde_json::json!({ "output-cache": false }));
+        let path = fx.plugin("a.wasm", &constant_plugin("_preprocess", Book::new(), None));
+        for _ in 0..2 {
+            assert!(!fx.run(&path, &Book::new()).unwrap().metrics.cached);
+        }
+        assert!(!fx.root.join("target").join("wasm-preprocessor-cache").exists());
+    }
+
+    #[test]
+    fn batch() {
+        let config = serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "format": "json",
+                },
+            },
+        });
+        // Each book has its own plugin, which
+        // replaces it with a book of its own
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for name in &["First", "Second"] {
+            let root = test_dir(&format!("batch-{}", name));
+            let mut book = Book::new();
+            book.push_item(Chapter::new(name, format!("The {} book", name), "index.md", Vec::new()));
+            std::fs::write(root.join("preprocessors").join("a.wasm"), constant_plugin("_preprocess", &book, None)).unwrap();
+            serde_json::to_writer(&mut input, &(context(&root, config.clone()), Book::new())).unwrap();
+            input.push(b'\n');
+            expected.push(book);
+        }
+        let mut output = Vec::new();
+        run_batch(input.as_slice(), &mut output, &[], None, false, false).unwrap();
+        let books: Vec<Book> = String::from_utf8(output).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(books, expected);
+    }
+
+    #[test]
+    fn missing_dir() {
+        capture_logs();
+        let fx = Fixture::new("missing-dir", serde_json::json!({ "dir": "missing" }));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Untouched", "WASM".to_string(), "untouched.md", Vec::new()));
+        let (ran, modified) = fx.run_all(book.clone(), false).unwrap();
+        assert_eq!(ran, book);
+        assert!(modified.is_empty());
+        let missing = format!("{:?}", fx.root.join("missing"));
+        assert!(logged().iter().any(|(level, msg)| *level == Level::Debug && msg.contains(&missing)));
+    }
+
+    #[test]
+    fn strict() {
+        let fx = Fixture::new("strict", serde_json::json!({}));
+        fx.plugin("bad.wasm", b"not a wasm module");
+        // By default the failing plugin is skipped
+        let (ran, modified) = fx.run_all(Book::new(), false).unwrap();
+        assert_eq!(ran, Book::new());
+        assert!(modified.is_empty());
+        match fx.run_all(Book::new(), true) {
+            Err(PluginError::Compile(msg)) => assert!(msg.contains("bad.wasm")),
+            other => panic!("expected a compile error, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn forwards_args() {
+        let mut ctx = context(Path::new("/books/example"), serde_json::json!({}));
+        forward_args(&mut ctx, &[]).unwrap();
+        assert!(plugin_host(&ctx).unwrap().args().is_empty());
+        forward_args(&mut ctx, &["--dark".to_string()]).unwrap();
+        assert_eq!(plugin_host(&ctx).unwrap().args(), ["--dark".to_string()]);
+    }
+
+    /// Without the `compress` feature the JSON
+    /// crosses the boundary as it is
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn json_format() {
+        let mut fx = Fixture::new("json-format", serde_json::json!({ "format": "bincode" }));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "Wasm is great, Wasm is fast".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "Nothing to see here".to_string(), "part_2.md", Vec::new()));
+        let output = SerializationFormat::Json.serialize(Ok::<_, String>(&book)).unwrap();
+        assert!(std::str::from_utf8(&output).is_ok());
+        let data: String = output.iter().map(|b| format!("\\{:02x}", b)).collect();
+        // Traps unless the [ctx, book] it was sent is a JSON array
+        let plugin = wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (data (i32.const 32768) "{data}")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    (if (i32.ne (i32.load8_u (get_local 0)) (i32.const 91))
+                        (then unreachable))
+                    i32.const 0
+                    i32.const {len}
+                    i32.store
+                    i32.const 32768))
+        "#, data = data, len = output.len())).unwrap();
+        let path = fx.plugin("assemblyscript.wasm", &plugin);
+        assert!(fx.run(&path, &Book::new()).is_err());
+        override_format(&mut fx.ctx, Some("json")).unwrap();
+        let (ran, _) = fx.run_all(Book::new(), true).unwrap();
+        assert_eq!(ran, book);
+        match override_format(&mut fx.ctx, Some("yaml")) {
+            Err(PluginError::Config(_)) => (),
+            other => panic!("expected a config error, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unhealthy() {
+        let fx = Fixture::new("unhealthy", serde_json::json!({ "health-check": true }));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Changed", "Never seen".to_string(), "changed.md", Vec::new()));
+        // Would replace the book if it were run
+        let health = r#"(func (export "_health") (result i32) i32.const 0)"#;
+        fx.plugin("a.wasm", &constant_plugin_with("_preprocess", &book, health));
+        let (ran, modified) = fx.run_all(Book::new(), false).unwrap();
+        assert_eq!(ran, Book::new());
+        assert!(modified.is_empty());
+        match fx.run_all(Book::new(), true) {
+            Err(PluginError::Unhealthy(msg)) => assert!(msg.contains("a.wasm")),
+            other => panic!("expected an unhealthy plugin, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn warnings() {
+        capture_logs();
+        let fx = Fixture::new("warnings", serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Linted", "Still used".to_string(), "linted.md", Vec::new()));
+        let warnings = vec!["deprecated directive on line 10", "unknown directive on line 12"];
+        fx.plugin("lint-warnings.wasm", &constant_plugin("_preprocess", PluginReturn {
+            warnings: warnings.iter().map(|w| w.to_string()).collect(),
+            ..PluginReturn::new(book.clone())
+        }, Some("__plugin_return")));
+        let (ran, _) = fx.run_all(Book::new(), false).unwrap();
+        assert_eq!(ran, book);
+        let logged = logged();
+        for warning in warnings {
+            let expected = format!("lint-warnings.wasm: {}", warning);
+            assert!(logged.iter().any(|(level, msg)| *level == Level::Warn && *msg == expected));
+        }
+    }
+
+    #[test]
+    fn rerun() {
+        capture_logs();
+        let fx = Fixture::new("rerun", serde_json::json!({}));
+        let mut first = Book::new();
+        first.push_item(Chapter::new("Draft", "Needs another pass".to_string(), "draft.md", Vec::new()));
+        let mut second = Book::new();
+        second.push_item(Chapter::new("Final", "Settled".to_string(), "final.md", Vec::new()));
+        let encode = |value| -> Vec<u8> {
+            SerializationFormat::Json.serialize(Ok::<_, String>(value)).unwrap()
+        };
+        let again = encode(PluginReturn { rerun: true, ..PluginReturn::new(first.clone()) });
+        let done = encode(PluginReturn::new(second.clone()));
+        let data = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("\\{:02x}", b)).collect() };
+        // Only the second pass sees `first`, every input
+        // shorter than that is the first pass
+        let second_input = SerializationFormat::Json.serialize((&fx.ctx, &first)).unwrap().len();
+        let plugin = wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (data (i32.const 32768) "{again}")
+                (data (i32.const 49152) "{done}")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "__plugin_return_preprocess"))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    (if (result i32) (i32.lt_u (get_local 1) (i32.const {second_input}))
+                        (then
+                            (i32.store (i32.const 0) (i32.const {again_len}))
+                            (i32.const 32768))
+                        (else
+                            (i32.store (i32.const 0) (i32.const {done_len}))
+                            (i32.const 49152)))))
+        "#, again = data(&again), done = data(&done), second_input = second_input, again_len = again.len(), done_len = done.len())).unwrap();
+        let path = fx.plugin("converge.wasm", &plugin);
+        let (ran, modified) = fx.run_all(Book::new(), false).unwrap();
+        assert_eq!(ran, second);
+        assert_eq!(modified[&path], vec![vec![0]]);
+        let logged = logged();
+        let ran_pass = |pass: usize| {
+            let expected = format!("pass {} over {:?}", pass, path.parent().unwrap());
+            logged.iter().any(|(level, msg)| *level == Level::Debug && *msg == expected)
+        };
+        assert!(ran_pass(2));
+        assert!(!ran_pass(3));
+    }
+
+    #[test]
+    fn no_bleed_between_passes() {
+        let fx = Fixture::new("no-bleed-between-passes", serde_json::json!({}));
+        let mut first = Book::new();
+        first.push_item(Chapter::new("Draft", "Needs another pass".to_string(), "draft.md", Vec::new()));
+        let mut second = Book::new();
+        second.push_item(Chapter::new("Final", "Settled".to_string(), "final.md", Vec::new()));
+        let encode = |value| -> Vec<u8> {
+            SerializationFormat::Json.serialize(Ok::<_, String>(value)).unwrap()
+        };
+        let again = encode(PluginReturn { rerun: true, ..PluginReturn::new(first.clone()) });
+        let done = encode(PluginReturn::new(second.clone()));
+        let data = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("\\{:02x}", b)).collect() };
+        // The same instance runs both passes, the second input is
+        // put somewhere else and the plugin traps if anything from
+        // the first is still in memory
+        let plugin = wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (global $calls (mut i32) (i32.const 0))
+                (data (i32.const 32768) "{again}")
+                (data (i32.const 49152) "{done}")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    (if (result i32) (get_global $calls)
+                        (then (i32.const 8192))
+                        (else (i32.const 16))))
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "__plugin_return_preprocess"))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    (if (i32.eqz (get_global $calls))
+                        (then
+                            (set_global $calls (i32.const 1))
+                            (i32.store (i32.const 0) (i32.const {again_len}))
+                            (return (i32.const 32768))))
+                    (if (i32.load (i32.const 16))
+                        (then unreachable))
+                    (i32.store (i32.const 0) (i32.const {done_len}))
+                    (i32.const 49152)))
+        "#, again = data(&again), done = data(&done), again_len = again.len(), done_len = done.len())).unwrap();
+        fx.plugin("bleed.wasm", &plugin);
+        let (ran, _) = fx.run_all(Book::new(), true).unwrap();
+        assert_eq!(ran, second);
+    }
+
+    #[test]
+    fn max_passes() {
+        let fx = Fixture::new("max-passes", serde_json::json!({ "max-passes": 1 }));
+        // Always asks for another pass, so only the cap stops it
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Never done", "Again".to_string(), "again.md", Vec::new()));
+        fx.plugin("again.wasm", &constant_plugin("_preprocess", PluginReturn { rerun: true, ..PluginReturn::new(book.clone()) }, Some("__plugin_return")));
+        let (ran, _) = fx.run_all(Book::new(), false).unwrap();
+        assert_eq!(ran, book);
+        let ctx = context(&fx.root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "max-passes": 0,
+                },
+            },
+        }));
+        match run_all_preprocessors(&fx.cache, &ctx, Book::new(), false, false) {
+            Err(PluginError::Config(_)) => (),
+            other => panic!("expected a config error, found {:?}", other),
+        }
+    }
+
+    /// Compressed, a cut short book fails
+    /// to decompress before it is read
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn truncated_book() {
+        let fx = Fixture::new("truncated-book", serde_json::json!({ "format": "bincode" }));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Cut short", "Never finished".to_string(), "cut.md", Vec::new()));
+        // The book is cut short but checksummed,
+        // so only deserializing it can fail
+        let mut bytes = SerializationFormat::Bincode.serialize(Ok::<_, String>(&book)).unwrap();
+        bytes.truncate(bytes.len() - wasmer_plugin_example::CHECKSUM_SIZE - 8);
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        let path = fx.plugin("truncated.wasm", &raw_plugin("_preprocess", &bytes, ""));
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::Serde(msg)) => {
+                assert!(msg.contains("truncated.wasm"), "no file name in {:?}", msg);
+                assert!(msg.contains(&format!("of {}", bytes.len())), "no offset in {:?}", msg);
+            },
+            other => panic!("expected a serialization error, found {:?}", other.map(|run| run.book)),
+        }
+    }
+
+    #[test]
+    fn invalid_book() {
+        let fx = Fixture::new("invalid-book", serde_json::json!({}));
+        // Loading this would fail with a compile error instead
+        fx.plugin("a.wasm", b"not wasm");
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Empty", String::new(), "", Vec::new()));
+        match fx.run_all(book, false) {
+            Err(PluginError::InvalidBook(_)) => (),
+            other => panic!("expected an invalid book, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn panic_message() {
+        // Panics with "chapter 3 is missing", which the
+        // panic hook would have left at address 2048
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (data (i32.const 2048) "chapter 3 is missing")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "__plugin_panic_message") (result i32)
+                    i32.const 0
+                    i32.const 20
+                    i32.store
+                    i32.const 2048)
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    unreachable))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(&test_dir("panic-message"), serde_json::json!({}));
+        match preprocess(&instance(&module), "panic.wasm", &ctx, &Book::new()) {
+            Err(PluginError::Panic(msg)) => assert_eq!(msg, "chapter 3 is missing"),
+            other => panic!("expected a panic, found {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn trap_after_panic() {
+        // Traps without panicking, the hook cleared the message
+        // an earlier call left so its length is 0
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "__plugin_panic_message") (result i32)
+                    i32.const 0
+                    i32.const 0
+                    i32.store
+                    i32.const 2048)
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    unreachable))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(&test_dir("trap-after-panic"), serde_json::json!({}));
+        match preprocess(&instance(&module), "trap.wasm", &ctx, &Book::new()) {
+            Err(PluginError::Trap(_)) => (),
+            other => panic!("expected a trap, found {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn not_a_plugin() {
+        let fx = Fixture::new("not-a-plugin", serde_json::json!({}));
+        let path = fx.plugin("other.wasm", &wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "_other") (param i32 i32) (result i32)
+                    i32.const 0))
+        "#).unwrap());
+        match fx.run(&path, &Book::new()) {
+            Err(PluginError::MissingExport(msg)) => assert!(msg.contains("_preprocess, found [_other]")),
+            other => panic!("expected a missing export error, found {:?}", other.map(|run| run.book)),
+        }
+    }
+
+    #[test]
+    fn list() {
+        let root = test_dir("list");
+        let dir = root.join("preprocessors");
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    i32.const 0))
+        "#).unwrap();
+        std::fs::write(dir.join("a.wasm"), wasm).unwrap();
+        std::fs::write(dir.join("b.wasm"), b"not a wasm module").unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        let listing = list_plugins(&ctx).unwrap();
+        assert_eq!(listing.len(), 2);
+        assert_eq!(listing[0], (dir.join("a.wasm"), Ok(())));
+        assert_eq!(listing[1].0, dir.join("b.wasm"));
+        assert!(listing[1].1.is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn list_example_plugin() {
+        let root = test_dir("list-example-plugin");
+        let path = root.join("preprocessors").join("example_plugin.wasm");
+        std::fs::write(&path, build_example_plugin()).unwrap();
+        // It logs, so it needs the host's imports
+        std::fs::write(path.with_extension("toml"), "imports = \"host\"").unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(list_plugins(&ctx).unwrap(), vec![(path, Ok(()))]);
+    }
+
+    #[test]
+    fn import_policy() {
+        let root = test_dir("import-policy");
+        let dir = root.join("preprocessors");
+        // Both plugins want to log
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (import "env" "host_log" (func $host_log (param i32 i32)))
+                (memory 1)
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    i32.const 0))
+        "#).unwrap();
+        std::fs::write(dir.join("trusted.wasm"), &wasm).unwrap();
+        std::fs::write(dir.join("trusted.toml"), "imports = \"host\"").unwrap();
+        std::fs::write(dir.join("untrusted.wasm"), &wasm).unwrap();
+        let host = PluginHost::default();
+        let module = compile(&wasm).unwrap();
+        let trusted = PluginManifest::load(&dir.join("trusted.wasm")).unwrap();
+        assert!(sandboxed(&host, &trusted).instantiate(&module).is_ok());
+        let untrusted = PluginManifest::load(&dir.join("untrusted.wasm")).unwrap();
+        match sandboxed(&host, &untrusted).instantiate(&module) {
+            Err(PluginError::Instantiate(_)) => (),
+            other => panic!("expected an instantiate error, found {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn configured_dir() {
+        let root = PathBuf::from("/books/example");
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(preprocessor_dir(&ctx), root.join("preprocessors"));
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "dir": "../shared-plugins",
+                },
+            },
+        }));
+        assert_eq!(preprocessor_dir(&ctx), root.join("../shared-plugins"));
+    }
+
+    #[test]
+    fn configured_format() {
+        let root = PathBuf::from("/books/example");
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(plugin_format(&ctx), Ok(default_format()));
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "format": "json",
+                },
+            },
+        }));
+        assert_eq!(plugin_format(&ctx), Ok(SerializationFormat::Json));
+    }
+
+    #[test]
+    fn sorted_plugins() {
+        let root = test_dir("sorted-plugins");
+        let dir = root.join("preprocessors");
+        for name in &["b.wasm", "c.wasm", "a.wasm", "notes.txt"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let names = |ctx: &PreprocessorContext| -> Vec<String> {
+            plugin_paths(ctx, &dir).unwrap()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(names(&ctx), vec!["a.wasm", "b.wasm", "c.wasm"]);
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "order": ["c.wasm"],
+                },
+            },
+        }));
+        assert_eq!(names(&ctx), vec!["c.wasm", "a.wasm", "b.wasm"]);
+    }
+
+    #[test]
+    fn configured_entry() {
+        // Two plugins in one module, both with no output
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_first") (param i32 i32) (result i32)
+                    i32.const 0
+                    i32.const 0
+                    i32.store
+                    i32.const 0)
+                (func (export "_second") (param i32 i32) (result i32)
+                    i32.const 0
+                    i32.const 0
+                    i32.store
+                    i32.const 0))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let root = Path::new("/books/example");
+        let ctx = context(root, serde_json::json!({}));
+        assert_eq!(plugin_entry(&ctx), "_preprocess");
+        assert!(preprocess(&instance(&module), "test.wasm", &ctx, &Book::new()).is_err());
+        let ctx = context(root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "entry": "_second",
+                },
+            },
+        }));
+        assert_eq!(preprocess(&instance(&module), "test.wasm", &ctx, &Book::new()).unwrap().book, Book::new());
+    }
+
+    /// Build the example plugin for wasm32 and read the module
+    /// back, this needs the `wasm32-unknown-unknown` target
+    /// installed (`rustup target add wasm32-unknown-unknown`)
+    fn build_example_plugin() -> Vec<u8> {
+        let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+        let status = std::process::Command::new(env!("CARGO"))
+            .args(&["build", "-p", "example-plugin", "--target", "wasm32-unknown-unknown"])
+            .current_dir(&workspace)
+            .status()
+            .expect("failed to run cargo");
+        assert!(status.success(), "failed to build the example plugin");
+        std::fs::read(workspace.join("target/wasm32-unknown-unknown/debug/example_plugin.wasm"))
+            .expect("failed to read the example plugin")
+    }
+
+    /// `plugin_helper` leaves out every export off wasm32, so the
+    /// example plugin and its tests should build for the host too
+    // Run with `cargo test -p mdbook-example-runner -- --ignored`
+    #[test]
+    #[ignore]
+    fn native_example_plugin() {
+        let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).join("../..");
+        let status = std::process::Command::new(env!("CARGO"))
+            .args(&["build", "-p", "example-plugin", "-p", "example-config-plugin", "--all-targets"])
+            .current_dir(&workspace)
+            .status()
+            .expect("failed to run cargo");
+        assert!(status.success(), "failed to build the example plugins natively");
+    }
+
+    // Run with `cargo test -p mdbook-example-runner -- --ignored`
+    #[test]
+    #[ignore]
+    fn example_plugin() {
+        let wasm = build_example_plugin();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(Path::new("/books/example"), serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great, WASM is fast".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "Nothing to see here".to_string(), "part_2.md", Vec::new()));
+        let PluginRun { book: updated, modified, metrics, .. } = preprocess(&instance(&module), "example_plugin.wasm", &ctx, &book).unwrap();
+        let contents: Vec<String> = updated.iter().filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some(ch.content.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(contents, vec![
+            "Wasm is great, Wasm is fast".to_string(),
+            "Nothing to see here".to_string(),
+        ]);
+        // Only the chapter mentioning WASM was touched
+        assert_eq!(modified, vec![vec![0]]);
+        assert!(metrics.bytes_in > 0);
+        assert!(metrics.bytes_out > 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn gzipped_example_plugin() {
+        let root = test_dir("gzipped-example-plugin");
+        let path = root.join("preprocessors").join("example_plugin.wasm.gz");
+        std::fs::write(&path, gzip(&build_example_plugin())).unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great, WASM is fast".to_string(), "part_1.md", Vec::new()));
+        let cache = ModuleCache::default();
+        let updated = run_preprocessor(&cache, &mut HashMap::new(), &PluginHost::default(), &Plugin::load(&path).unwrap(), &ctx, &book).unwrap().book;
+        let contents: Vec<String> = updated.iter().filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some(ch.content.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(contents, vec!["Wasm is great, Wasm is fast".to_string()]);
+    }
+
+    #[test]
+    #[ignore]
+    fn example_plugin_from_bytes() {
+        let root = test_dir("example-plugin-from-bytes");
+        let ctx = context(&root, serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great, WASM is fast".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "Nothing to see here".to_string(), "part_2.md", Vec::new()));
+        let plugins = vec![("example_plugin.wasm".to_string(), build_example_plugin())];
+        let (updated, modified) = run_preprocessors_from_bytes(&ModuleCache::default(), &ctx, book, plugins).unwrap();
+        let contents: Vec<String> = updated.iter().filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some(ch.content.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(contents, vec![
+            "Wasm is great, Wasm is fast".to_string(),
+            "Nothing to see here".to_string(),
+        ]);
+        assert_eq!(modified[Path::new("example_plugin.wasm")], vec![vec![0]]);
+    }
+
+    #[test]
+    #[ignore]
+    fn preprocess_one_example_plugin() {
+        let root = test_dir("preprocess-one-example-plugin");
+        let dir = root.join("preprocessors");
+        std::fs::write(dir.join("example_plugin.wasm"), build_example_plugin()).unwrap();
+        std::fs::write(dir.join("example_plugin.toml"), "imports = \"host\"").unwrap();
+        // This one answers in JSON, so running it
+        // as well would fail with a bincode error
+        std::fs::write(dir.join("empty.wasm"), constant_plugin("_preprocess", Book::new(), None)).unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great, WASM is fast".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "Nothing to see here".to_string(), "part_2.md", Vec::new()));
+        let (updated, modified) = preprocess_one(&ModuleCache::default(), &ctx, book, "example_plugin.wasm").unwrap();
+        let contents: Vec<String> = updated.iter().filter_map(|item| match item {
+            BookItem::Chapter(ch) => Some(ch.content.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(contents, vec![
+            "Wasm is great, Wasm is fast".to_string(),
+            "Nothing to see here".to_string(),
+        ]);
+        assert_eq!(modified, vec![vec![0]]);
+    }
+
+    #[test]
+    fn preprocess_one_missing() {
+        let root = test_dir("preprocess-one-missing");
+        std::fs::write(root.join("preprocessors").join("a.wasm"), constant_plugin("_preprocess", Book::new(), None)).unwrap();
+        let ctx = context(&root, serde_json::json!({}));
+        match preprocess_one(&ModuleCache::default(), &ctx, Book::new(), "b.wasm") {
+            Err(PluginError::NotFound(msg)) => assert!(msg.contains("b.wasm")),
+            other => panic!("expected a missing plugin, found {:?}", other),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn native_matches_wasm() {
+        let wasm = build_example_plugin();
+        let plugin = instance(&compile(&wasm).unwrap());
+        let native = wasmer_plugin_example::NativePlugin::new()
+            .register("_preprocess", |(ctx, book): (PreprocessorContext, Book)| example_plugin::preprocess(ctx, book));
+        let ctx = context(Path::new("/books/example"), serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great, WASM is fast".to_string(), "part_1.md", Vec::new()));
+        let from_wasm: Book = plugin.call("_preprocess", (&ctx, &book)).unwrap();
+        let from_native: Book = native.call("_preprocess", (&ctx, &book)).unwrap();
+        assert_eq!(from_native, from_wasm);
+    }
+
+    #[test]
+    #[ignore]
+    fn example_plugin_delta() {
+        let wasm = build_example_plugin();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(Path::new("/books/example"), serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "entry": "_wasm_to_wasm",
+                },
+            },
+        }));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "Nothing to see here".to_string(), "part_2.md", Vec::new()));
+        let plugin = instance(&module);
+        assert!(plugin.returns_plugin_return("_wasm_to_wasm"));
+        let updated = preprocess(&plugin, "example_plugin.wasm", &ctx, &book).unwrap().book;
+        let mut expected = book.clone();
+        if let BookItem::Chapter(ch) = &mut expected.sections[0] {
+            ch.content = "Wasm is great".to_string();
+        }
+        assert_eq!(updated, expected);
+    }
+
+    #[test]
+    fn unchanged_keeps_book() {
+        // Says the book is unchanged and hands back a
+        // pointer that would trap if it was read
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    i32.const 0
+                    i32.const -1
+                    i32.store
+                    i32.const -1))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(Path::new("/books/example"), serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM".to_string(), "part_1.md", Vec::new()));
+        let PluginRun { book: updated, metrics, .. } = preprocess(&instance(&module), "test.wasm", &ctx, &book).unwrap();
+        assert_eq!(updated, book);
+        // The book was sent but nothing came back
+        assert!(metrics.bytes_in > 0);
+        assert_eq!(metrics.bytes_out, 0);
+    }
+
+    #[test]
+    fn unsupported_renderer() {
+        // Declines every renderer, running it would trap
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_supports") (param i32 i32) (result i32)
+                    i32.const 0)
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    unreachable))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(Path::new("/books/example"), serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM".to_string(), "part_1.md", Vec::new()));
+        let updated = preprocess(&instance(&module), "test.wasm", &ctx, &book).unwrap().book;
+        assert_eq!(updated, book);
+    }
+
+    #[test]
+    fn configured_host() {
+        let root = PathBuf::from("/books/example");
+        let ctx = context(&root, serde_json::json!({}));
+        assert_eq!(plugin_host(&ctx).unwrap().timeout(), None);
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "format": "json",
+                    "timeout": 250,
+                    "env": {
+                        "sha": "1a4f38e",
+                    },
+                    "seed": 1234,
+                },
+            },
+        }));
+        let host = plugin_host(&ctx).unwrap();
+        assert_eq!(host.format(), SerializationFormat::Json);
+        assert_eq!(host.timeout(), Some(Duration::from_millis(250)));
+        assert_eq!(host.env(), Some(&serde_json::json!({"sha": "1a4f38e"})));
+        assert_eq!(host.seed(), Some(1234));
+        assert!(host.args().is_empty());
+        let ctx = context(&root, serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "timeout": "soon",
+                },
+            },
+        }));
+        assert!(plugin_host(&ctx).is_err());
+    }
+
+    #[test]
+    fn fuel() {
+        assert_eq!(super::fuel(None), Ok(None));
+        assert_eq!(super::fuel(Some("50".to_string())), Ok(Some(Duration::from_millis(50))));
+        assert!(super::fuel(Some("lots".to_string())).is_err());
+    }
+
+    #[test]
+    fn out_of_fuel() {
+        let fx = Fixture::new("out-of-fuel", serde_json::json!({}));
+        let path = fx.plugin("spin.wasm", &wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    (loop $forever
+                        (br $forever))
+                    i32.const 0))
+        "#).unwrap());
+        let host = PluginHost::builder()
+            .with_timeout(super::fuel(Some("50".to_string())).unwrap().unwrap())
+            .build();
+        match run_preprocessor(&fx.cache, &mut HashMap::new(), &host, &Plugin::load(&path).unwrap(), &fx.ctx, &Book::new()) {
+            Err(PluginError::Timeout(msg)) => assert!(msg.contains("spin.wasm")),
+            other => panic!("expected a timeout, found {:?}", other.map(|run| run.book)),
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let ctx = context(Path::new("/books/example"), serde_json::json!({
+            "preprocessor": {
+                "wasm": {
+                    "max-payload-bytes": 64,
+                },
+            },
+        }));
+        let host = plugin_host(&ctx).unwrap();
+        assert_eq!(host.max_payload_bytes(), 64);
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    i32.const 0))
+        "#).unwrap();
+        let plugin = host.instantiate(&compile(&wasm).unwrap()).unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM is great, WASM is fast".repeat(10), "part_1.md", Vec::new()));
+        match preprocess(&plugin, "test.wasm", &ctx, &book) {
+            Err(PluginError::TooLarge(_)) => (),
+            other => panic!("expected too large, found {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn no_output_keeps_book() {
+        // A plugin that only has side effects, it
+        // writes a length of 0 and returns 0
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_preprocess") (param i32 i32) (result i32)
+                    i32.const 0
+                    i32.const 0
+                    i32.store
+                    i32.const 0))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let ctx = context(Path::new("/books/example"), serde_json::json!({}));
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "WASM".to_string(), "part_1.md", Vec::new()));
+        let updated = preprocess(&instance(&module), "test.wasm", &ctx, &book).unwrap().book;
+        assert_eq!(updated, book);
+    }
+}
@@ -0,0 +1,101 @@
+// ./crates/example-runner/src/manifest.rs
+use serde::Deserialize;
+use std::{
+    fs,
+    path::Path,
+};
+use wasmer_plugin_example::{
+    host_imports,
+    PluginError,
+};
+use wasmer_runtime::{
+    imports,
+    ImportObject,
+};
+
+/// Settings for a single plugin, read from a `.toml`
+/// file with the same name sitting next to the `.wasm`
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PluginManifest {
+    /// The plugin only edits chapter content and can run
+    /// alongside other parallel plugins on a copy of the book
+    pub parallel: bool,
+    /// The host functions the plugin can import
+    pub imports: ImportPolicy,
+}
+
+/// Which of the runner's functions a plugin is given, a plugin
+/// that imports anything it wasn't given fails to instantiate
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportPolicy {
+    /// Nothing at all, plugins are untrusted unless
+    /// their manifest says otherwise
+    None,
+    /// Everything in `host_imports`, like `host_log`
+    Host,
+}
+
+impl Default for ImportPolicy {
+    fn default() -> Self {
+        ImportPolicy::None
+    }
+}
+
+impl ImportPolicy {
+    /// The imports a plugin with this policy is instantiated with
+    pub fn imports(self) -> fn() -> ImportObject {
+        match self {
+            ImportPolicy::None => no_imports,
+            ImportPolicy::Host => host_imports,
+        }
+    }
+}
+
+fn no_imports() -> ImportObject {
+    imports! {}
+}
+
+impl PluginManifest {
+    /// Load the manifest for the plugin at `path`, a
+    /// plugin without one gets the defaults
+    pub fn load(path: &Path) -> Result<Self, PluginError> {
+        // A gzipped `plugin.wasm.gz` uses `plugin.toml` too
+        let path = if path.extension().map(|ext| ext == "gz").unwrap_or(false) {
+            path.with_extension("")
+        } else {
+            path.to_path_buf()
+        };
+        let sidecar = path.with_extension("toml");
+        if !sidecar.exists() {
+            return Ok(Self::default());
+        }
+        let s = fs::read_to_string(&sidecar)
+            .map_err(|e| PluginError::Io(format!("{:?}, {}", sidecar, e)))?;
+        toml::from_str(&s)
+            .map_err(|e| PluginError::Config(format!("{:?}, {}", sidecar, e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir().join("example-runner-manifest");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let plugin = dir.join("plugin.wasm");
+        assert_eq!(PluginManifest::load(&plugin).unwrap(), PluginManifest::default());
+        std::fs::write(dir.join("plugin.toml"), "parallel = true").unwrap();
+        assert!(PluginManifest::load(&plugin).unwrap().parallel);
+        assert_eq!(PluginManifest::load(&plugin).unwrap().imports, ImportPolicy::None);
+        std::fs::write(dir.join("plugin.toml"), "imports = \"host\"").unwrap();
+        assert_eq!(PluginManifest::load(&plugin).unwrap().imports, ImportPolicy::Host);
+        assert_eq!(PluginManifest::load(&dir.join("plugin.wasm.gz")).unwrap().imports, ImportPolicy::Host);
+        std::fs::write(dir.join("plugin.toml"), "parallel = 1").unwrap();
+        assert!(PluginManifest::load(&plugin).is_err());
+    }
+}
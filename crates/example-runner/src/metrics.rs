@@ -0,0 +1,68 @@
+// ./crates/example-runner/src/metrics.rs
+use log::info;
+use std::time::Duration;
+
+/// How long a single plugin took and how much
+/// data crossed into and out of its memory
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PluginMetrics {
+    /// The plugin's file name
+    pub name: String,
+    /// Compiling and instantiating the module
+    pub load: Duration,
+    /// Calling the plugin, including marshalling
+    pub call: Duration,
+    /// The bytes written into the plugin's memory
+    pub bytes_in: usize,
+    /// The bytes read back out of the plugin's memory
+    pub bytes_out: usize,
+    /// The output came from the output cache
+    /// and the plugin wasn't called
+    pub cached: bool,
+}
+
+/// Log the metrics for every plugin that ran
+pub fn report(metrics: &[PluginMetrics]) {
+    for line in lines(metrics) {
+        info!("{}", line);
+    }
+}
+
+fn lines(metrics: &[PluginMetrics]) -> Vec<String> {
+    metrics.iter()
+        .map(|m| if m.cached {
+            format!("{}: reused its cached output", m.name)
+        } else {
+            format!(
+                "{}: loaded in {:?}, ran in {:?}, {} bytes in, {} bytes out",
+                m.name, m.load, m.call, m.bytes_in, m.bytes_out,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let metrics = PluginMetrics {
+            name: "example.wasm".to_string(),
+            load: Duration::from_millis(3),
+            call: Duration::from_millis(1),
+            bytes_in: 120,
+            bytes_out: 118,
+            cached: false,
+        };
+        let cached = PluginMetrics {
+            name: "cached.wasm".to_string(),
+            cached: true,
+            ..PluginMetrics::default()
+        };
+        assert_eq!(super::lines(&[metrics, cached]), vec![
+            "example.wasm: loaded in 3ms, ran in 1ms, 120 bytes in, 118 bytes out".to_string(),
+            "cached.wasm: reused its cached output".to_string(),
+        ]);
+    }
+}
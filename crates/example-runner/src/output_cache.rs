@@ -0,0 +1,100 @@
+// ./crates/example-runner/src/output_cache.rs
+use mdbook::{
+    book::Book,
+    preprocess::PreprocessorContext,
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    path::PathBuf,
+};
+use wasmer_plugin_example::PluginError;
+
+/// Everything a plugin can hand back, most only send the
+/// book. This is what is cached for a given input, with any
+/// delta already applied, since it is all a later run needs
+/// to skip calling the plugin
+pub type PluginReturn = wasmer_plugin_example::PluginReturn<Book, PreprocessorContext>;
+
+/// Plugin outputs saved under the book's `target` directory,
+/// one file per plugin and input. A key covers the plugin's
+/// bytes along with the context and book it was sent, so
+/// changing any of them misses
+pub struct OutputCache {
+    dir: PathBuf,
+}
+
+impl OutputCache {
+    pub fn new(ctx: &PreprocessorContext) -> Self {
+        Self {
+            dir: ctx.root.join("target").join("wasm-preprocessor-cache"),
+        }
+    }
+
+    /// The key for running the plugin `plugin` over `ctx` and `book`
+    pub fn key(plugin: &[u8], ctx: &PreprocessorContext, book: &Book) -> Result<u64, PluginError> {
+        let input = serde_json::to_vec(&(ctx, book))
+            .map_err(|e| PluginError::Serde(e.to_string()))?;
+        let mut hasher = DefaultHasher::new();
+        plugin.hash(&mut hasher);
+        input.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    /// The output saved for `key`, a missing or unreadable
+    /// entry is a miss
+    pub fn get(&self, key: u64) -> Option<PluginReturn> {
+        let bytes = fs::read(self.path(key)).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    pub fn put(&self, key: u64, output: &PluginReturn) -> Result<(), PluginError> {
+        let bytes = serde_json::to_vec(output)
+            .map_err(|e| PluginError::Serde(e.to_string()))?;
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| PluginError::Io(format!("{:?} {}", self.dir, e)))?;
+        fs::write(self.path(key), bytes)
+            .map_err(|e| PluginError::Io(format!("{:?} {}", self.path(key), e)))
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::book::Chapter;
+
+    #[test]
+    fn round_trip() {
+        let root = std::env::temp_dir().join("example-runner-output-cache");
+        let _ = fs::remove_dir_all(&root);
+        let ctx: PreprocessorContext = serde_json::from_value(serde_json::json!({
+            "root": root,
+            "config": {},
+            "renderer": "html",
+            "mdbook_version": "0.2.3",
+        })).unwrap();
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Intro", "All about WASM".to_string(), "intro.md", Vec::new()));
+        let cache = OutputCache::new(&ctx);
+        let key = OutputCache::key(b"plugin", &ctx, &book).unwrap();
+        assert!(cache.get(key).is_none());
+        let output = PluginReturn {
+            files: vec![(PathBuf::from("api.md"), "# API".to_string())],
+            warnings: vec!["chapter 1 has no title".to_string()],
+            ..PluginReturn::new(book.clone())
+        };
+        cache.put(key, &output).unwrap();
+        assert_eq!(cache.get(key), Some(output));
+        // Another plugin or another book is another key
+        assert_ne!(OutputCache::key(b"other plugin", &ctx, &book).unwrap(), key);
+        assert_ne!(OutputCache::key(b"plugin", &ctx, &Book::new()).unwrap(), key);
+    }
+}
@@ -0,0 +1,135 @@
+// ./crates/example-runner/src/parallel.rs
+use mdbook::book::{
+    Book,
+    BookItem,
+};
+use log::{
+    error,
+    warn,
+};
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    thread,
+};
+use wasmer_plugin_example::PluginError;
+
+/// Run each of the plugins at `paths` at the same time, each on
+/// its own copy of `book`, then merge their chapter edits back
+/// together. When two plugins edit the same chapter the one that
+/// comes later in `paths` wins.
+///
+/// Plugins run this way may only change chapter content, one that
+/// changes the book's structure has its edits dropped
+pub fn run_parallel<F>(paths: &[PathBuf], book: Book, run: F) -> Book
+where F: Fn(&Path, &Book) -> Result<Book, PluginError> + Sync {
+    if paths.is_empty() {
+        return book;
+    }
+    let results: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = paths.iter()
+            .map(|path| {
+                let run = &run;
+                let book = &book;
+                s.spawn(move || run(path, book))
+            })
+            .collect();
+        handles.into_iter()
+            .map(|h| h.join().expect("Plugin thread panicked"))
+            .collect()
+    });
+    let original = chapter_contents(&book.sections);
+    let mut merged = book.clone();
+    for (path, result) in paths.iter().zip(results) {
+        let updated = match result {
+            Ok(updated) => updated,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            },
+        };
+        let contents = chapter_contents(&updated.sections);
+        if contents.len() != original.len() {
+            warn!("{:?} changed the structure of the book, parallel plugins may only edit chapters", path);
+            continue;
+        }
+        let mut edits = contents.into_iter()
+            .zip(original.iter())
+            .map(|(new, old)| if &new != old { Some(new) } else { None });
+        apply_edits(&mut merged.sections, &mut edits);
+    }
+    merged
+}
+
+/// The content of every chapter, parents
+/// come before their sub chapters
+fn chapter_contents(items: &[BookItem]) -> Vec<String> {
+    let mut ret = Vec::new();
+    for item in items {
+        if let BookItem::Chapter(ch) = item {
+            ret.push(ch.content.clone());
+            ret.extend(chapter_contents(&ch.sub_items));
+        }
+    }
+    ret
+}
+
+/// Replace the content of each chapter that has an edit,
+/// `edits` lines up with `chapter_contents`
+fn apply_edits(items: &mut [BookItem], edits: &mut dyn Iterator<Item = Option<String>>) {
+    for item in items {
+        if let BookItem::Chapter(ch) = item {
+            if let Some(Some(content)) = edits.next() {
+                ch.content = content;
+            }
+            apply_edits(&mut ch.sub_items, edits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::book::Chapter;
+
+    fn book() -> Book {
+        let mut book = Book::new();
+        book.push_item(Chapter::new("Part 1", "one".to_string(), "part_1.md", Vec::new()));
+        book.push_item(Chapter::new("Part 2", "two".to_string(), "part_2.md", Vec::new()));
+        book
+    }
+
+    /// Uppercase the chapter at `idx`
+    fn shout(book: &Book, idx: usize) -> Book {
+        let mut book = book.clone();
+        if let BookItem::Chapter(ch) = &mut book.sections[idx] {
+            ch.content = ch.content.to_uppercase();
+        }
+        book
+    }
+
+    #[test]
+    fn merges_edits() {
+        let paths = vec![PathBuf::from("first.wasm"), PathBuf::from("second.wasm")];
+        let merged = run_parallel(&paths, book(), |path, book| {
+            let idx = if path.ends_with("first.wasm") { 0 } else { 1 };
+            Ok(shout(book, idx))
+        });
+        assert_eq!(chapter_contents(&merged.sections), vec!["ONE", "TWO"]);
+    }
+
+    #[test]
+    fn skips_failures() {
+        let paths = vec![PathBuf::from("first.wasm"), PathBuf::from("second.wasm")];
+        let merged = run_parallel(&paths, book(), |path, book| {
+            if path.ends_with("first.wasm") {
+                Err(PluginError::Trap("unreachable".to_string()))
+            } else {
+                Ok(shout(book, 1))
+            }
+        });
+        assert_eq!(chapter_contents(&merged.sections), vec!["one", "TWO"]);
+    }
+}
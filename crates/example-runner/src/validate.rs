@@ -0,0 +1,105 @@
+// ./crates/example-runner/src/validate.rs
+use mdbook::book::{
+    Book,
+    BookItem,
+};
+use std::path::{
+    Component,
+    Path,
+};
+use wasmer_plugin_example::{
+    ChapterPath,
+    PluginError,
+};
+
+/// Check the book read from stdin before any plugin sees it.
+/// Every chapter, including sub chapters, needs a name and a
+/// path relative to the book's source directory.
+///
+/// A book without any chapters is fine, mdBook builds one
+pub fn validate_book(book: &Book) -> Result<(), PluginError> {
+    validate_items(&book.sections, &mut Vec::new())
+}
+
+fn validate_items(items: &[BookItem], path: &mut ChapterPath) -> Result<(), PluginError> {
+    for (i, item) in items.iter().enumerate() {
+        let ch = match item {
+            BookItem::Chapter(ch) => ch,
+            _ => continue,
+        };
+        path.push(i);
+        if ch.name.trim().is_empty() {
+            return Err(PluginError::InvalidBook(format!("chapter {:?} has no name", path)));
+        }
+        if ch.path.as_os_str().is_empty() {
+            return Err(PluginError::InvalidBook(format!("chapter {:?} ({}) has an empty path", path, ch.name)));
+        }
+        if !is_inside(&ch.path) {
+            return Err(PluginError::InvalidBook(format!("chapter {:?} ({}) has the path {:?} outside of the book", path, ch.name, ch.path)));
+        }
+        validate_items(&ch.sub_items, path)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Check a file a plugin returned can be written to the
+/// book's src, it can't be empty or lead out of the src
+pub fn validate_file_path(path: &Path) -> Result<(), PluginError> {
+    if path.as_os_str().is_empty() || !is_inside(path) {
+        return Err(PluginError::OutsideBook(format!("{:?}", path)));
+    }
+    Ok(())
+}
+
+/// If `path` stays inside of the directory it is relative to
+fn is_inside(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::book::Chapter;
+
+    fn book(why_path: &str) -> Book {
+        let mut intro = Chapter::new("Intro", "All about WASM".to_string(), "intro.md", Vec::new());
+        intro.sub_items.push(BookItem::Chapter(Chapter::new("Why", "WASM is fast".to_string(), why_path, Vec::new())));
+        let mut book = Book::new();
+        book.push_item(intro);
+        book.push_item(BookItem::Separator);
+        book
+    }
+
+    #[test]
+    fn valid() {
+        assert!(validate_book(&book("intro/why.md")).is_ok());
+        assert!(validate_book(&Book::new()).is_ok());
+    }
+
+    #[test]
+    fn empty_path() {
+        match validate_book(&book("")) {
+            Err(PluginError::InvalidBook(msg)) => assert_eq!(msg, "chapter [0, 0] (Why) has an empty path"),
+            other => panic!("expected an invalid book, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn outside_of_book() {
+        assert!(validate_book(&book("../why.md")).is_err());
+        assert!(validate_book(&book("/why.md")).is_err());
+    }
+
+    #[test]
+    fn file_paths() {
+        assert!(validate_file_path(Path::new("api/index.md")).is_ok());
+        assert!(validate_file_path(Path::new("")).is_err());
+        assert!(validate_file_path(Path::new("../README.md")).is_err());
+        assert!(validate_file_path(Path::new("api/../../README.md")).is_err());
+        assert!(validate_file_path(Path::new("/etc/passwd")).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// ./src/error.rs
+use std::{
+    error::Error,
+    fmt,
+};
+
+/// Everything that can go wrong running a plugin
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginError {
+    /// A plugin file couldn't be read
+    Io(String),
+    /// The host was configured incorrectly
+    Config(String),
+    /// The module could not be compiled
+    Compile(String),
+    /// The module could not be instantiated
+    Instantiate(String),
+    /// The module doesn't export a function we need
+    MissingExport(String),
+    /// The plugin trapped while running
+    Trap(String),
+    /// The plugin's memory couldn't hold what we needed
+    Memory(String),
+    /// Data could not be serialized or deserialized
+    Serde(String),
+    /// The plugin didn't write any output
+    NoOutput(String),
+    /// The plugin ran but returned an error
+    Plugin(String),
+    /// The plugin didn't finish in time
+    Timeout(String),
+    /// A payload failed its checksum
+    Corrupt(String),
+    /// The host sent a different type than the plugin expects
+    TypeMismatch(String),
+    /// The plugin panicked, with the message it panicked with
+    Panic(String),
+    /// The input was over the host's payload limit
+    TooLarge(String),
+    /// The book handed to the host is malformed
+    InvalidBook(String),
+    /// The plugin returned a file outside of the book
+    OutsideBook(String),
+    /// The plugin was built for another version of the memory protocol
+    AbiMismatch(String),
+    /// The plugin's health check reported it isn't healthy
+    Unhealthy(String),
+    /// There is no plugin with the name asked for
+    NotFound(String),
+}
+
+impl PluginError {
+    /// Prefix this error's message with `name`, this
+    /// is used to say which plugin an error came from
+    pub fn named(self, name: &str) -> Self {
+        let prefix = |msg: String| format!("{}: {}", name, msg);
+        match self {
+            PluginError::Io(msg) => PluginError::Io(prefix(msg)),
+            PluginError::Config(msg) => PluginError::Config(prefix(msg)),
+            PluginError::Compile(msg) => PluginError::Compile(prefix(msg)),
+            PluginError::Instantiate(msg) => PluginError::Instantiate(prefix(msg)),
+            PluginError::MissingExport(msg) => PluginError::MissingExport(prefix(msg)),
+            PluginError::Trap(msg) => PluginError::Trap(prefix(msg)),
+            PluginError::Memory(msg) => PluginError::Memory(prefix(msg)),
+            PluginError::Serde(msg) => PluginError::Serde(prefix(msg)),
+            PluginError::NoOutput(msg) => PluginError::NoOutput(prefix(msg)),
+            PluginError::Plugin(msg) => PluginError::Plugin(prefix(msg)),
+            PluginError::Timeout(msg) => PluginError::Timeout(prefix(msg)),
+            PluginError::Corrupt(msg) => PluginError::Corrupt(prefix(msg)),
+            PluginError::TypeMismatch(msg) => PluginError::TypeMismatch(prefix(msg)),
+            PluginError::Panic(msg) => PluginError::Panic(prefix(msg)),
+            PluginError::TooLarge(msg) => PluginError::TooLarge(prefix(msg)),
+            PluginError::InvalidBook(msg) => PluginError::InvalidBook(prefix(msg)),
+            PluginError::OutsideBook(msg) => PluginError::OutsideBook(prefix(msg)),
+            PluginError::AbiMismatch(msg) => PluginError::AbiMismatch(prefix(msg)),
+            PluginError::Unhealthy(msg) => PluginError::Unhealthy(prefix(msg)),
+            PluginError::NotFound(msg) => PluginError::NotFound(prefix(msg)),
+        }
+    }
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginError::Io(msg) => write!(f, "Error reading plugin {}", msg),
+            PluginError::Config(msg) => write!(f, "Error in plugin configuration {}", msg),
+            PluginError::Compile(msg) => write!(f, "Error compiling wasm module {}", msg),
+            PluginError::Instantiate(msg) => write!(f, "Error instantiating wasm module {}", msg),
+            PluginError::MissingExport(name) => write!(f, "Module is missing the export {}", name),
+            PluginError::Trap(msg) => write!(f, "Plugin trapped {}", msg),
+            PluginError::Memory(msg) => write!(f, "Error with plugin memory {}", msg),
+            PluginError::Serde(msg) => write!(f, "Error serializing plugin data {}", msg),
+            PluginError::NoOutput(name) => write!(f, "{} did not return any output", name),
+            PluginError::Plugin(msg) => write!(f, "Plugin returned an error: {}", msg),
+            PluginError::Timeout(msg) => write!(f, "Plugin timed out {}", msg),
+            PluginError::Corrupt(msg) => write!(f, "Corrupt plugin data {}", msg),
+            PluginError::TypeMismatch(msg) => write!(f, "Plugin input type mismatch {}", msg),
+            PluginError::Panic(msg) => write!(f, "Plugin panicked {}", msg),
+            PluginError::TooLarge(msg) => write!(f, "Plugin input too large {}", msg),
+            PluginError::InvalidBook(msg) => write!(f, "Invalid book {}", msg),
+            PluginError::OutsideBook(msg) => write!(f, "Plugin file outside of the book {}", msg),
+            PluginError::AbiMismatch(msg) => write!(f, "Plugin ABI mismatch {}", msg),
+            PluginError::Unhealthy(msg) => write!(f, "Plugin unhealthy {}", msg),
+            PluginError::NotFound(msg) => write!(f, "Plugin not found {}", msg),
+        }
+    }
+}
+
+impl Error for PluginError {}
+
+impl From<PluginError> for String {
+    fn from(e: PluginError) -> String {
+        e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn named() {
+        let e = PluginError::MissingExport("_preprocess".to_string()).named("bad.wasm");
+        assert_eq!(e, PluginError::MissingExport("bad.wasm: _preprocess".to_string()));
+        let msg: String = e.into();
+        assert_eq!(msg, "Module is missing the export bad.wasm: _preprocess");
+    }
+}
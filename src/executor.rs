@@ -0,0 +1,63 @@
+// ./src/executor.rs
+use std::{
+    future::Future,
+    ptr,
+    task::{
+        Context,
+        Poll,
+        RawWaker,
+        RawWakerVTable,
+        Waker,
+    },
+};
+
+/// Run `future` to completion on the current thread, this
+/// is how `plugin_helper` calls an `async fn`.
+///
+/// Nothing inside a plugin can wake a task up, so this
+/// just keeps polling until the future is ready
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(ret) = future.as_mut().poll(&mut cx) {
+            return ret;
+        }
+    }
+}
+
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(ptr::null(), &VTABLE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Not ready the first `n` times it is polled
+    struct Yield(u8);
+
+    impl Future for Yield {
+        type Output = u8;
+        fn poll(mut self: std::pin::Pin<&mut Self>, _cx: &mut Context) -> Poll<u8> {
+            if self.0 == 0 {
+                Poll::Ready(42)
+            } else {
+                self.0 -= 1;
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn block_on_pending() {
+        assert_eq!(block_on(Yield(3)), 42);
+        assert_eq!(block_on(async { 2u8 * 2 }), 4);
+    }
+}
@@ -0,0 +1,157 @@
+// ./src/format.rs
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    error::Error,
+    fmt,
+    str::FromStr,
+};
+
+/// An error from serializing or deserializing in any format
+pub type FormatError = Box<dyn Error + Send + Sync>;
+
+/// How data is encoded when it crosses the wasm boundary.
+///
+/// A plugin and its host need to agree on this, a plugin built
+/// with `#[plugin_helper(format = "json")]` needs to be run by
+/// a host using `SerializationFormat::Json`.
+///
+/// | format          | `plugin_helper`          | backend      | feature       |
+/// |-----------------|--------------------------|--------------|---------------|
+/// | `Bincode`       | `format = "bincode"`     | `bincode`    |               |
+/// | `Json`          | `format = "json"`        | `serde_json` |               |
+/// | `MessagePack`   | `format = "messagepack"` | `rmp-serde`  | `messagepack` |
+/// | `Cbor`          | `format = "cbor"`        | `serde_cbor` | `cbor`        |
+///
+/// Only bincode is wrapped in the wire header and checksum,
+/// CBOR is the best fit for guests not written in rust
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerializationFormat {
+    Bincode,
+    Json,
+    #[cfg(feature = "messagepack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Default for SerializationFormat {
+    fn default() -> Self {
+        SerializationFormat::Bincode
+    }
+}
+
+impl SerializationFormat {
+    /// Serialize the value provided in this format
+    pub fn serialize<S>(self, s: S) -> Result<Vec<u8>, FormatError>
+    where S: Serialize {
+        match self {
+            SerializationFormat::Bincode => Ok(crate::try_revert_data(s)?),
+            SerializationFormat::Json => Ok(crate::try_revert_data_json(s)?),
+            #[cfg(feature = "messagepack")]
+            SerializationFormat::MessagePack => Ok(crate::try_revert_data_msgpack(s)?),
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => Ok(crate::try_revert_data_cbor(s)?),
+        }
+    }
+
+    /// Deserialize a value from bytes in this format
+    pub fn deserialize<'a, D>(self, bytes: &'a [u8]) -> Result<D, FormatError>
+    where D: crate::FromPayload<'a> {
+        match self {
+            SerializationFormat::Bincode => Ok(crate::try_convert_data(bytes)?),
+            SerializationFormat::Json => Ok(crate::try_convert_data_json(bytes)?),
+            #[cfg(feature = "messagepack")]
+            SerializationFormat::MessagePack => Ok(crate::try_convert_data_msgpack(bytes)?),
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => Ok(crate::try_convert_data_cbor(bytes)?),
+        }
+    }
+
+    /// Deserialize a value that doesn't borrow from `bytes`,
+    /// so it can outlive a view into a plugin's memory
+    pub fn deserialize_owned<D>(self, bytes: &[u8]) -> Result<D, FormatError>
+    where D: DeserializeOwned {
+        match self {
+            SerializationFormat::Bincode => Ok(crate::try_convert_data_owned(bytes)?),
+            _ => self.deserialize(bytes),
+        }
+    }
+}
+
+impl FromStr for SerializationFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(SerializationFormat::Bincode),
+            "json" => Ok(SerializationFormat::Json),
+            #[cfg(feature = "messagepack")]
+            "messagepack" => Ok(SerializationFormat::MessagePack),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(SerializationFormat::Cbor),
+            _ => Err(format!("Unknown serialization format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SerializationFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerializationFormat::Bincode => write!(f, "bincode"),
+            SerializationFormat::Json => write!(f, "json"),
+            #[cfg(feature = "messagepack")]
+            SerializationFormat::MessagePack => write!(f, "messagepack"),
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(format: SerializationFormat) {
+        let pair = (2u8, String::from("attributed"));
+        let bytes = format.serialize(&pair).unwrap();
+        let back: (u8, String) = format.deserialize(&bytes).unwrap();
+        assert_eq!(back, pair);
+    }
+
+    #[test]
+    fn bincode() {
+        round_trip(SerializationFormat::Bincode);
+    }
+
+    #[test]
+    fn json() {
+        round_trip(SerializationFormat::Json);
+    }
+
+    // Compressed it is no longer readable
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn json_bytes() {
+        let bytes = SerializationFormat::Json.serialize((2u8, "attributed")).unwrap();
+        assert_eq!(bytes, br#"[2,"attributed"]"#.to_vec());
+    }
+
+    #[cfg(feature = "messagepack")]
+    #[test]
+    fn messagepack() {
+        round_trip(SerializationFormat::MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        round_trip(SerializationFormat::Cbor);
+        assert_eq!("cbor".parse(), Ok(SerializationFormat::Cbor));
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!("json".parse(), Ok(SerializationFormat::Json));
+        assert_eq!("bincode".parse(), Ok(SerializationFormat::Bincode));
+        assert!("yaml".parse::<SerializationFormat>().is_err());
+    }
+}
@@ -0,0 +1,1603 @@
+// ./src/host.rs
+use serde::{
+    Serialize,
+    de::DeserializeOwned,
+};
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    ffi::c_void,
+    io::{
+        stderr,
+        Write,
+    },
+    sync::mpsc::{
+        channel,
+        RecvTimeoutError,
+    },
+    thread,
+    time::Duration,
+};
+use wasmer_runtime::{
+    func,
+    imports,
+    Ctx,
+    Export,
+    Func,
+    ImportObject,
+    Instance,
+    Memory,
+    Module,
+    units::Pages,
+};
+use crate::{
+    check_payload,
+    ABI_VERSION,
+    read_bytes,
+    write_bytes,
+    MemoryLayout,
+    PluginError,
+    PluginExport,
+    SerializationFormat,
+};
+
+/// The size of a single page of wasm memory
+const WASM_PAGE_SIZE: usize = 65_536;
+
+/// The exports every plugin gets from this crate for
+/// the host to hand it settings, not plugin functions
+const HOST_SETTERS: &[&str] = &["_set_env", "_set_seed", "_set_args"];
+
+/// How many times a call that ran off the end of memory is
+/// retried with another page, a plugin needing more than this
+/// is more likely broken than short on memory
+const OUT_OF_BOUNDS_RETRIES: usize = 1;
+
+/// The import namespaces a plugin built for `wasm32-wasi`
+/// asks for, which wasmer 0.3 has no import object for
+const WASI_NAMESPACES: &[&str] = &["wasi_unstable", "wasi_snapshot_preview1"];
+
+/// A wasm plugin that has been instantiated and can be called
+/// with any value the plugin knows how to deserialize
+pub struct PluginInstance {
+    instance: Instance,
+    format: SerializationFormat,
+    // Boxed so the instance's context can
+    // keep a pointer to it
+    log: Box<PluginLog>,
+    // The regions of memory the host has written
+    // input to and not yet handed back with `__plugin_free`
+    scratch: RefCell<Vec<(usize, usize)>>,
+    // The bytes sent to and read back from
+    // the plugin over every call so far
+    marshalled: Cell<(usize, usize)>,
+    // Compare input types with the plugin's
+    // before every call
+    type_checks: bool,
+    // The most bytes a single input can be
+    max_payload_bytes: usize,
+    // The memory inputs and outputs are copied through
+    memory_index: u32,
+}
+
+/// Where the plugin's `host_log` calls end up
+struct PluginLog {
+    prefix: Option<String>,
+    sink: Box<dyn Write>,
+    // The memory messages are read out of
+    memory_index: u32,
+}
+
+/// The functions the host provides to every plugin, a
+/// custom set of imports should start from these
+pub fn host_imports() -> ImportObject {
+    imports! {
+        "env" => {
+            "host_log" => func!(host_log),
+        },
+    }
+}
+
+/// Write a message from the plugin, a message that
+/// isn't valid UTF-8 has the bad bytes replaced and
+/// one outside of memory is reported as such. The
+/// message's destination comes from the `PluginInstance`
+/// the plugin runs in, before there is one, like in a
+/// start function, it goes to stderr
+pub(crate) fn host_log(ctx: &mut Ctx, ptr: i32, len: u32) {
+    if ctx.data.is_null() {
+        let msg = log_message(ctx, 0, ptr, len);
+        eprintln!("{}", msg);
+        return;
+    }
+    // Only a `PluginInstance` sets the context's data,
+    // and it always points it at the instance's log
+    let log = unsafe { &mut *(ctx.data as *mut PluginLog) };
+    let msg = log_message(ctx, log.memory_index, ptr, len);
+    let _ = match &log.prefix {
+        Some(prefix) => writeln!(log.sink, "{}: {}", prefix, msg),
+        None => writeln!(log.sink, "{}", msg),
+    };
+}
+
+/// Read the message `host_log` was called with out of memory
+fn log_message(ctx: &Ctx, memory_index: u32, ptr: i32, len: u32) -> String {
+    let view = ctx.memory(memory_index).view::<u8>();
+    let start = ptr as usize;
+    let end = start.saturating_add(len as usize);
+    if end <= view.len() {
+        String::from_utf8_lossy(&read_bytes(&view[start..end])).to_string()
+    } else {
+        format!("<log message at {}..{} is outside of memory>", start, end)
+    }
+}
+
+/// Copy `bytes` into `memory` at `ptr`, a region the plugin
+/// reserved with `__plugin_alloc`, and zero out the length
+/// of the plugin's output. Memory is grown if the region
+/// runs off the end of it
+pub fn write_input(memory: &Memory, ptr: i32, bytes: &[u8]) -> Result<(), PluginError> {
+    let start = ptr as u32 as usize;
+    // Make sure the whole region fits in memory, if it
+    // doesn't anything past the end would silently be
+    // dropped when we copy it over
+    let end = start + bytes.len();
+    let size = memory.view::<u8>().len();
+    if end > size {
+        let pages = (end - size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+        memory.grow(Pages(pages as u32))
+            .map_err(|e| PluginError::Memory(format!("growing by {} pages {:?}", pages, e)))?;
+    }
+    // Now we can get a view of that memory, this needs
+    // to happen after growing it
+    let view = memory.view::<u8>();
+    MemoryLayout::write_len(&view, 0);
+    // Copy our bytes into the region the plugin reserved
+    write_bytes(&view[start..end], bytes);
+    Ok(())
+}
+
+/// Read the output a plugin left in `memory` starting
+/// at `ptr`, using the length it wrote. A length of 0
+/// means the plugin had no output and a length of
+/// `MemoryLayout::UNCHANGED` means its input is still
+/// current, either way there is nothing to read.
+///
+/// This copies the output out of memory, use
+/// `read_output_borrowed` to work with it in place
+pub fn read_output(memory: &Memory, ptr: i32) -> Result<Option<Vec<u8>>, PluginError> {
+    read_output_borrowed(memory, ptr, |bytes| bytes.to_vec())
+}
+
+/// Hand the output a plugin left in `memory` at `ptr` to `f`
+/// without copying it, anything that needs the bytes to
+/// outlive `f` has to copy them. `None` means there was no
+/// output, the same as `read_output`
+pub fn read_output_borrowed<F, R>(memory: &Memory, ptr: i32, f: F) -> Result<Option<R>, PluginError>
+where F: FnOnce(&[u8]) -> R {
+    let view = memory.view::<u8>();
+    let start = ptr as u32 as usize;
+    let len = MemoryLayout::read_len(&view);
+    if len == 0 || len == MemoryLayout::UNCHANGED {
+        return Ok(None);
+    }
+    let len = len as usize;
+    // A plugin could hand back anything here so it needs
+    // to be checked before we slice into memory
+    let end = start.checked_add(len)
+        .filter(|end| *end <= view.len())
+        .ok_or_else(|| PluginError::Trap(format!(
+            "output at {} with length {} is outside of memory ({} bytes)",
+            start, len, view.len(),
+        )))?;
+    let cells = &view[start..end];
+    // A `Cell<u8>` has the same layout as a `u8` and nothing
+    // can change memory while `f` runs, the plugin can only
+    // run again once we hand control back
+    let bytes = unsafe {
+        ::std::slice::from_raw_parts(cells.as_ptr() as *const u8, cells.len())
+    };
+    Ok(Some(f(bytes)))
+}
+
+/// Copy the UTF-8 string of `len` bytes at `ptr` out of
+/// `memory`, for plugins that take or return a plain string
+/// instead of serialized data
+pub fn read_string(memory: &Memory, ptr: i32, len: u32) -> Result<String, PluginError> {
+    let view = memory.view::<u8>();
+    let start = ptr as u32 as usize;
+    let end = start.checked_add(len as usize)
+        .filter(|end| *end <= view.len())
+        .ok_or_else(|| PluginError::Memory(format!(
+            "string at {} with length {} is outside of memory ({} bytes)",
+            start, len, view.len(),
+        )))?;
+    String::from_utf8(read_bytes(&view[start..end]))
+        .map_err(|e| PluginError::Serde(e.to_string()))
+}
+
+/// Copy `s` into `memory` at `ptr`, a region the plugin reserved
+/// with `__plugin_alloc`, the same way `write_input` does. This
+/// returns the pointer and length to call the plugin with
+pub fn write_string(memory: &Memory, ptr: i32, s: &str) -> Result<(i32, u32), PluginError> {
+    write_input(memory, ptr, s.as_bytes())?;
+    Ok((ptr, s.len() as u32))
+}
+
+/// Serialize `input` the way every plugin expects its
+/// input, this is shared with `NativePlugin`
+pub(crate) fn encode_input<I: Serialize>(format: SerializationFormat, input: I) -> Result<Vec<u8>, PluginError> {
+    format.serialize(input)
+        .map_err(|e| PluginError::Serde(e.to_string()))
+}
+
+/// Deserialize the `Result` a plugin handed back in
+/// `bytes`, this is shared with `NativePlugin`
+pub(crate) fn decode_output<O: DeserializeOwned>(format: SerializationFormat, bytes: &[u8]) -> Result<Result<O, String>, PluginError> {
+    // Catch a cut short bincode payload here so it isn't
+    // reported as a confusing deserialization error, a
+    // compressed one already fails to decompress
+    if cfg!(not(feature = "compress")) && format == SerializationFormat::Bincode {
+        check_payload(bytes)?;
+    }
+    format.deserialize_owned::<Result<O, String>>(bytes)
+        .map_err(|e| PluginError::Serde(e.to_string()))
+}
+
+/// Set the `len` bytes from `start` to 0, anything
+/// past the end of memory is left alone
+fn zero_region(view: &[Cell<u8>], start: usize, len: usize) {
+    let end = (start + len).min(view.len());
+    for cell in view.get(start..end).unwrap_or(&[]) {
+        cell.set(0);
+    }
+}
+
+/// If `e` is a trap from reading or writing outside of memory,
+/// an index outside of a table is out of bounds too but more
+/// memory won't fix it
+fn is_out_of_bounds(e: &PluginError) -> bool {
+    match e {
+        PluginError::Trap(msg) => {
+            let msg = msg.to_lowercase();
+            (msg.contains("out of bounds") || msg.contains("out-of-bounds"))
+                && !msg.contains("table")
+                && !msg.contains("indirect")
+        },
+        _ => false,
+    }
+}
+
+/// The error for a call to `export` that failed, which
+/// includes why the plugin trapped, like hitting an
+/// `unreachable` or reading outside of its memory
+fn trapped<E: std::fmt::Display>(export: &str, e: E) -> PluginError {
+    PluginError::Trap(format!("in {}, {}", export, e))
+}
+
+/// Say why instantiating failed when a plugin wanted WASI
+/// imports, a missing `fd_read` alone reads like a typo
+/// in the plugin rather than the wrong target
+fn explain_wasi(msg: String) -> String {
+    if WASI_NAMESPACES.iter().any(|ns| msg.contains(ns)) {
+        format!("{}, plugins built for wasm32-wasi aren't supported, build for wasm32-unknown-unknown instead", msg)
+    } else {
+        msg
+    }
+}
+
+/// Drop the paths and whitespace from a type name so the
+/// name `type_name` gives and the one `plugin_helper` wrote
+/// down are the same for the same type
+fn strip_type_paths(name: &str) -> String {
+    let mut stripped = String::new();
+    let mut ident = String::new();
+    for c in name.chars() {
+        match c {
+            c if c.is_alphanumeric() || c == '_' => ident.push(c),
+            // Anything before a `::` is a path
+            ':' => ident.clear(),
+            c => {
+                stripped.push_str(&ident);
+                ident.clear();
+                if !c.is_whitespace() {
+                    stripped.push(c);
+                }
+            },
+        }
+    }
+    stripped.push_str(&ident);
+    stripped
+}
+
+/// Run `f` on its own thread, giving up on it if it takes longer
+/// than `timeout`. `name` is used in the error
+pub(crate) fn run_with_timeout<F, R>(name: &str, timeout: Option<Duration>, f: F) -> Result<R, PluginError>
+where F: FnOnce() -> Result<R, PluginError> + Send + 'static,
+      R: Send + 'static {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        // The receiver is gone if we timed out
+        let _ = tx.send(f());
+    });
+    let ret = match timeout {
+        Some(timeout) => rx.recv_timeout(timeout),
+        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    match ret {
+        Ok(ret) => ret,
+        Err(RecvTimeoutError::Timeout) => Err(PluginError::Timeout(format!("{} after {:?}", name, timeout.unwrap_or_default()))),
+        Err(RecvTimeoutError::Disconnected) => Err(PluginError::Trap(format!("{} panicked", name))),
+    }
+}
+
+impl PluginInstance {
+    /// The largest input a plugin is sent unless
+    /// `with_max_payload_bytes` says otherwise, 256MiB
+    pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 256 * 1024 * 1024;
+
+    /// Wrap an already instantiated plugin
+    pub fn new(mut instance: Instance) -> Self {
+        let mut log = Box::new(PluginLog {
+            prefix: None,
+            sink: Box::new(stderr()),
+            memory_index: 0,
+        });
+        instance.context_mut().data = &mut *log as *mut PluginLog as *mut c_void;
+        Self {
+            instance,
+            format: SerializationFormat::default(),
+            log,
+            scratch: RefCell::new(Vec::new()),
+            marshalled: Cell::new((0, 0)),
+            type_checks: false,
+            max_payload_bytes: Self::DEFAULT_MAX_PAYLOAD_BYTES,
+            memory_index: 0,
+        }
+    }
+
+    /// Instantiate a compiled module with the
+    /// functions the host provides
+    pub fn from_module(module: &Module) -> Result<Self, PluginError> {
+        Self::from_module_with_imports(module, &host_imports())
+    }
+
+    /// Instantiate a compiled module with `imports` in place
+    /// of `host_imports`, anything from `host_imports` a plugin
+    /// needs has to be included
+    pub fn from_module_with_imports(module: &Module, imports: &ImportObject) -> Result<Self, PluginError> {
+        let instance = module.instantiate(imports)
+            .map_err(|e| PluginError::Instantiate(explain_wasi(e.to_string())))?;
+        Ok(Self::new(instance))
+    }
+
+    /// Prefix anything the plugin logs with `name`
+    pub fn with_log_name(mut self, name: &str) -> Self {
+        self.log.prefix = Some(name.to_string());
+        self
+    }
+
+    /// Send anything the plugin logs to `sink`
+    /// instead of stderr
+    pub fn with_log_sink<W: Write + 'static>(mut self, sink: W) -> Self {
+        self.log.sink = Box::new(sink);
+        self
+    }
+
+    /// Use `format` for the data sent to and
+    /// received from this plugin
+    pub fn with_format(mut self, format: SerializationFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Copy data through the memory at `index` instead of memory
+    /// 0, for modules with more than one memory. The index needs
+    /// to be one of the module's memories
+    pub fn with_memory_index(mut self, index: u32) -> Result<Self, PluginError> {
+        let count = self.memory_count();
+        if index >= count {
+            return Err(PluginError::Memory(format!("there is no memory {}, the plugin has {}", index, count)));
+        }
+        self.memory_index = index;
+        self.log.memory_index = index;
+        Ok(self)
+    }
+
+    /// The number of memories the host can copy data through,
+    /// wasmer only shows us the ones a module exports. Memory 0
+    /// is always counted since the ABI needs it, whether it is
+    /// exported or not
+    fn memory_count(&self) -> u32 {
+        let exported = self.instance.exports()
+            .filter(|(_, export)| match export {
+                Export::Memory(_) => true,
+                _ => false,
+            })
+            .count() as u32;
+        exported.max(1)
+    }
+
+    /// The memory data is copied through, picked
+    /// with `with_memory_index`
+    fn memory(&self) -> &Memory {
+        self.instance.context().memory(self.memory_index)
+    }
+
+    /// Refuse to send an input over `max` bytes, they
+    /// fail with `PluginError::TooLarge` before anything
+    /// is copied into the plugin's memory
+    pub fn with_max_payload_bytes(mut self, max: usize) -> Self {
+        self.max_payload_bytes = max;
+        self
+    }
+
+    /// Check the type of every input against the type the plugin
+    /// function expects before calling it, see `check_input`
+    pub fn with_type_checks(mut self, type_checks: bool) -> Self {
+        self.type_checks = type_checks;
+        self
+    }
+
+    /// The underlying wasmer instance
+    pub fn instance(&self) -> &Instance {
+        &self.instance
+    }
+
+    /// The number of bytes written into the plugin's memory
+    /// and read back out of it, over every call so far.
+    /// These are the sizes on the wire, after compression
+    pub fn bytes_marshalled(&self) -> (usize, usize) {
+        self.marshalled.get()
+    }
+
+    /// The plugin functions this instance exports, these
+    /// are the functions starting with a single `_` since
+    /// a `__` prefix is used for the plugin's helpers.
+    /// The setters such as `_set_env` are left out since every plugin has them
+    pub fn exports(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.instance.exports()
+            .filter(|(name, export)| match export {
+                Export::Function { .. } => name.starts_with('_') && !name.starts_with("__") && !HOST_SETTERS.contains(&name.as_str()),
+                _ => false,
+            })
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The functions listed by the plugin's `plugin_registry!`,
+    /// along with the types they expect
+    pub fn registry(&self) -> Result<Vec<PluginExport>, PluginError> {
+        // The registry is always bincode, no matter what
+        // format this plugin's functions use
+        self.call_with_format(SerializationFormat::Bincode, "__plugin_exports", ())?
+            .ok_or_else(|| PluginError::NoOutput("__plugin_exports".to_string()))
+    }
+
+    /// Call the exported function `name` with `input`, it is
+    /// an error for the plugin to not return anything.
+    ///
+    /// `O` is whatever the plugin function returns, a function
+    /// returning several values as a tuple is read back into
+    /// a tuple of the same types in the same order
+    pub fn call<I, O>(&self, name: &str, input: I) -> Result<O, PluginError>
+    where I: Serialize,
+          O: DeserializeOwned {
+        self.call_optional(name, input)?
+            .ok_or_else(|| PluginError::NoOutput(name.to_string()))
+    }
+
+    /// Call `name` on a plugin created by `make` on its own
+    /// thread, giving up if it takes longer than `timeout`.
+    ///
+    /// The instance only ever lives on that thread, so when
+    /// the deadline passes the thread is abandoned and the
+    /// instance, along with whatever the plugin had written
+    /// to its memory, is torn down whenever it finishes
+    pub fn spawn_call<F, I, O>(make: F, name: &str, input: I, timeout: Option<Duration>) -> Result<Option<O>, PluginError>
+    where F: FnOnce() -> Result<PluginInstance, PluginError> + Send + 'static,
+          I: Serialize + Send + 'static,
+          O: DeserializeOwned + Send + 'static {
+        let thread_name = name.to_string();
+        run_with_timeout(name, timeout, move || {
+            make().and_then(|plugin| plugin.call_optional(&thread_name, input))
+        })
+    }
+
+    /// Write `input` into the instance's memory, call the
+    /// exported function `name` and read the result back out.
+    ///
+    /// Plugins always hand back a serialized `Result<O, String>`,
+    /// with bincode the 4 bytes after the wire header are the
+    /// variant, 0 for `Ok` and 1 for `Err`, followed by either
+    /// the value or the error message. A plugin that returns `()`
+    /// or `PluginOutput::Unchanged` has no output, which is `None`
+    pub fn call_optional<I, O>(&self, name: &str, input: I) -> Result<Option<O>, PluginError>
+    where I: Serialize,
+          O: DeserializeOwned {
+        if self.type_checks {
+            self.check_input::<I>(name)?;
+        }
+        self.call_with_format(self.format, name, input)
+    }
+
+    /// Compare `I` with the input type the plugin function `name`
+    /// was written for, read from its `__input_type_of` export.
+    ///
+    /// The names are compared without their paths, so a borrow
+    /// doesn't match the owned type the plugin reads. A function
+    /// without the export, like one not built with
+    /// `plugin_helper`, passes
+    pub fn check_input<I>(&self, name: &str) -> Result<(), PluginError> {
+        let input_type = match self.instance.func::<(), i32>(&format!("__input_type_of{}", name)) {
+            Ok(input_type) => input_type,
+            Err(_) => return Ok(()),
+        };
+        let ptr = input_type.call()
+            .map_err(|e| trapped(&format!("__input_type_of{}", name), e))?;
+        let expected = read_output(self.memory(), ptr)?
+            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+            .unwrap_or_default();
+        let found = std::any::type_name::<I>();
+        if strip_type_paths(&expected) != strip_type_paths(found) {
+            return Err(PluginError::TypeMismatch(format!("{} expects {} but was sent {}", name, expected, found)));
+        }
+        Ok(())
+    }
+
+    /// If the export `name` returns a `PluginReturn` instead of
+    /// only its output, `plugin_helper` marks these for us
+    pub fn returns_plugin_return(&self, name: &str) -> bool {
+        self.instance.func::<(), ()>(&format!("__plugin_return{}", name)).is_ok()
+    }
+
+    /// Hand `env` to the plugin's `_set_env(ptr, len)`, this is
+    /// always JSON whatever format the instance uses so a guest
+    /// in any language can read it. A plugin that doesn't export
+    /// `_set_env` has no use for it and is left alone
+    pub fn set_env(&self, env: &serde_json::Value) -> Result<(), PluginError> {
+        self.set_json("_set_env", env)
+    }
+
+    /// Hand `args` to the plugin's `_set_args(ptr, len)` as a
+    /// JSON array of strings, like `set_env` a plugin that
+    /// doesn't export `_set_args` is left alone
+    pub fn set_args(&self, args: &[String]) -> Result<(), PluginError> {
+        self.set_json("_set_args", args)
+    }
+
+    /// Call the setter `name` with `value` as JSON,
+    /// if the plugin exports it
+    fn set_json<T: Serialize + ?Sized>(&self, name: &str, value: &T) -> Result<(), PluginError> {
+        let set = match self.instance.func::<(i32, u32), ()>(name) {
+            Ok(set) => set,
+            Err(_) => return Ok(()),
+        };
+        let alloc = self.instance.func::<u32, i32>("__plugin_alloc")
+            .map_err(|_| PluginError::MissingExport("__plugin_alloc".to_string()))?;
+        let free = self.instance.func::<(i32, u32), ()>("__plugin_free")
+            .map_err(|_| PluginError::MissingExport("__plugin_free".to_string()))?;
+        let bytes = serde_json::to_vec(value)
+            .map_err(|e| PluginError::Serde(e.to_string()))?;
+        let ptr = self.write_input(&alloc, &bytes)?;
+        set.call(ptr, bytes.len() as u32)
+            .map_err(|e| trapped(name, e))?;
+        self.free_region(&free, ptr, bytes.len())
+    }
+
+    /// Hand `seed` to the plugin's `_set_seed(u64)`, a plugin
+    /// that doesn't export it has no randomness to seed
+    pub fn set_seed(&self, seed: u64) -> Result<(), PluginError> {
+        match self.instance.func::<u64, ()>("_set_seed") {
+            Ok(set_seed) => set_seed.call(seed)
+                .map_err(|e| trapped("_set_seed", e)),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// The message of the last panic in the plugin, read from
+    /// `__plugin_panic_message` after a call traps. `None` if the
+    /// plugin doesn't export it or didn't panic in that call
+    pub fn panic_message(&self) -> Option<String> {
+        let message = self.instance.func::<(), i32>("__plugin_panic_message").ok()?;
+        let ptr = message.call().ok()?;
+        let bytes = read_output(self.memory(), ptr).ok()??;
+        if bytes.is_empty() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Fail with `PluginError::AbiMismatch` unless the plugin's
+    /// `__abi_version` is `ABI_VERSION`. A plugin from before
+    /// the version was exported is assumed to be current
+    pub fn check_abi(&self) -> Result<(), PluginError> {
+        let version = match self.instance.func::<(), u32>("__abi_version") {
+            Ok(version) => version,
+            Err(_) => return Ok(()),
+        };
+        let version = version.call()
+            .map_err(|e| trapped("__abi_version", e))?;
+        if version != ABI_VERSION {
+            return Err(PluginError::AbiMismatch(format!("plugin was built for version {} but the host speaks version {}", version, ABI_VERSION)));
+        }
+        Ok(())
+    }
+
+    /// Call the plugin's `_health`, which returns 1 if the plugin
+    /// is ready to run or 0 if it isn't. A plugin that doesn't
+    /// export `_health` is healthy
+    pub fn healthy(&self) -> Result<bool, PluginError> {
+        match self.instance.func::<(), i32>("_health") {
+            Ok(health) => health.call()
+                .map(|ret| ret != 0)
+                .map_err(|e| trapped("_health", e)),
+            Err(_) => Ok(true),
+        }
+    }
+
+    /// Ask the plugin if it supports `renderer`, a plugin
+    /// that doesn't export `_supports` supports everything.
+    ///
+    /// `_supports` is passed the renderer's name as UTF-8
+    /// and returns 1 if it supports it or 0 if it doesn't
+    pub fn supports(&self, renderer: &str) -> Result<bool, PluginError> {
+        let supports = match self.instance.func::<(i32, u32), i32>("_supports") {
+            Ok(supports) => supports,
+            Err(_) => return Ok(true),
+        };
+        let alloc = self.instance.func::<u32, i32>("__plugin_alloc")
+            .map_err(|_| PluginError::MissingExport("__plugin_alloc".to_string()))?;
+        let free = self.instance.func::<(i32, u32), ()>("__plugin_free")
+            .map_err(|_| PluginError::MissingExport("__plugin_free".to_string()))?;
+        let len = renderer.len();
+        let ptr = self.write_input(&alloc, renderer.as_bytes())?;
+        let ret = supports.call(ptr, len as u32)
+            .map_err(|e| trapped("_supports", e))?;
+        self.free_region(&free, ptr, len)?;
+        Ok(ret != 0)
+    }
+
+    /// Fail with `PluginError::TooLarge` if an input
+    /// of `len` bytes is over the payload limit
+    fn check_size(&self, len: usize) -> Result<(), PluginError> {
+        if len > self.max_payload_bytes {
+            return Err(PluginError::TooLarge(format!("{} bytes is over the limit of {} bytes", len, self.max_payload_bytes)));
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` into a region reserved with `alloc`
+    /// and return the start of that region, as long as
+    /// they fit in the payload limit
+    fn write_input(&self, alloc: &Func<u32, i32>, bytes: &[u8]) -> Result<i32, PluginError> {
+        self.check_size(bytes.len())?;
+        // Ask the plugin to reserve space for our bytes
+        // so we don't write over anything it is using
+        let ptr = alloc.call(bytes.len() as u32)
+            .map_err(|e| trapped("__plugin_alloc", e))?;
+        write_input(self.memory(), ptr, bytes)?;
+        self.scratch.borrow_mut().push((ptr as u32 as usize, bytes.len()));
+        let (bytes_in, bytes_out) = self.marshalled.get();
+        self.marshalled.set((bytes_in + bytes.len(), bytes_out));
+        Ok(ptr)
+    }
+
+    /// Zero out every region the host has written input to that
+    /// it hasn't handed back yet, along with the output length.
+    /// Every region is zeroed as it's freed, so together nothing
+    /// from one call can be read back by the next
+    pub fn reset_memory(&self) {
+        let view = self.memory().view::<u8>();
+        for (start, len) in self.scratch.borrow_mut().drain(..) {
+            zero_region(&view, start, len);
+        }
+        MemoryLayout::write_len(&view, 0);
+    }
+
+    /// Zero the `len` bytes at `ptr` and hand them back to the
+    /// plugin with `free`. This has to happen in that order, once
+    /// a region is freed the plugin's allocator keeps its own
+    /// bookkeeping there and zeroing it would corrupt the heap
+    fn free_region(&self, free: &Func<(i32, u32), ()>, ptr: i32, len: usize) -> Result<(), PluginError> {
+        let start = ptr as u32 as usize;
+        zero_region(&self.memory().view::<u8>(), start, len);
+        self.scratch.borrow_mut().retain(|region| *region != (start, len));
+        free.call(ptr, len as u32)
+            .map_err(|e| trapped("__plugin_free", e))
+    }
+
+    fn call_with_format<I, O>(&self, format: SerializationFormat, name: &str, input: I) -> Result<Option<O>, PluginError>
+    where I: Serialize,
+          O: DeserializeOwned {
+        // Bind everything we need up front so a module that
+        // isn't a plugin fails before we serialize anything
+        let wasm_func = self.instance.func::<(i32, u32), i32>(name)
+            .map_err(|_| PluginError::MissingExport(name.to_string()))?;
+        let alloc = self.instance.func::<u32, i32>("__plugin_alloc")
+            .map_err(|_| PluginError::MissingExport("__plugin_alloc".to_string()))?;
+        let free = self.instance.func::<(i32, u32), ()>("__plugin_free")
+            .map_err(|_| PluginError::MissingExport("__plugin_free".to_string()))?;
+        let bytes = encode_input(format, input)?;
+        // Call the helper function an store the start of the returned string
+        let (ptr, start) = self.call_growing(name, &wasm_func, &alloc, &free, &bytes)?;
+        let ret = self.finish_call(format, &free, start);
+        self.free_region(&free, ptr, bytes.len())?;
+        ret
+    }
+
+    /// Copy `bytes` into memory and call `func` with them, returning
+    /// where they were written along with what `func` returned. When
+    /// it traps reading or writing past the end of memory it is given
+    /// another page and called again, up to `OUT_OF_BOUNDS_RETRIES`
+    /// times. Each call gets its own copy of `bytes`, the call that
+    /// trapped may have written over the one it was given, and that
+    /// copy is freed before the next call or the error is returned
+    fn call_growing(&self, name: &str, func: &Func<(i32, u32), i32>, alloc: &Func<u32, i32>, free: &Func<(i32, u32), ()>, bytes: &[u8]) -> Result<(i32, i32), PluginError> {
+        let mut retries = 0;
+        loop {
+            let ptr = self.write_input(alloc, bytes)?;
+            let e = match func.call(ptr, bytes.len() as u32) {
+                Ok(start) => return Ok((ptr, start)),
+                Err(e) => trapped(name, e),
+            };
+            let retry = retries < OUT_OF_BOUNDS_RETRIES && is_out_of_bounds(&e);
+            let freed = self.free_region(free, ptr, bytes.len());
+            if !retry {
+                // Why the call trapped matters more than
+                // the plugin not being able to free after it
+                return Err(e);
+            }
+            freed?;
+            self.memory().grow(Pages(1))
+                .map_err(|grow| PluginError::Memory(format!("growing after {}, {:?}", e, grow)))?;
+            retries += 1;
+        }
+    }
+
+    /// Send `input` to the export `name` in chunks of at most
+    /// `chunk_size` bytes, so it never needs to be in memory all
+    /// at once on the host's side of the boundary.
+    ///
+    /// Each chunk is copied into a region from `__plugin_alloc`,
+    /// passed to `__plugin_feed_chunk(ptr, len)` and then freed.
+    /// After the last chunk `__plugin_finish` followed by `name`
+    /// is called with no arguments, its output is the same as
+    /// calling `name` directly
+    pub fn call_chunked<I, O>(&self, name: &str, input: I, chunk_size: usize) -> Result<Option<O>, PluginError>
+    where I: Serialize,
+          O: DeserializeOwned {
+        let finish_name = format!("__plugin_finish{}", name);
+        let finish = self.instance.func::<(), i32>(&finish_name)
+            .map_err(|_| PluginError::MissingExport(finish_name.clone()))?;
+        let feed = self.instance.func::<(i32, u32), ()>("__plugin_feed_chunk")
+            .map_err(|_| PluginError::MissingExport("__plugin_feed_chunk".to_string()))?;
+        let alloc = self.instance.func::<u32, i32>("__plugin_alloc")
+            .map_err(|_| PluginError::MissingExport("__plugin_alloc".to_string()))?;
+        let free = self.instance.func::<(i32, u32), ()>("__plugin_free")
+            .map_err(|_| PluginError::MissingExport("__plugin_free".to_string()))?;
+        let bytes = encode_input(self.format, input)?;
+        // The limit is on the whole input, not each chunk
+        self.check_size(bytes.len())?;
+        for chunk in bytes.chunks(chunk_size.max(1)) {
+            let ptr = self.write_input(&alloc, chunk)?;
+            feed.call(ptr, chunk.len() as u32)
+                .map_err(|e| trapped("__plugin_feed_chunk", e))?;
+            self.free_region(&free, ptr, chunk.len())?;
+        }
+        let start = finish.call()
+            .map_err(|e| trapped(&finish_name, e))?;
+        self.finish_call(self.format, &free, start)
+    }
+
+    /// Call an export marked `#[plugin_helper(string)]`, the string
+    /// is copied in and out as is so nothing is serialized or
+    /// compressed on either side. An empty output is an empty string
+    pub fn call_string(&self, name: &str, input: &str) -> Result<String, PluginError> {
+        let wasm_func = self.instance.func::<(i32, u32), i32>(name)
+            .map_err(|_| PluginError::MissingExport(name.to_string()))?;
+        let alloc = self.instance.func::<u32, i32>("__plugin_alloc")
+            .map_err(|_| PluginError::MissingExport("__plugin_alloc".to_string()))?;
+        let free = self.instance.func::<(i32, u32), ()>("__plugin_free")
+            .map_err(|_| PluginError::MissingExport("__plugin_free".to_string()))?;
+        let ptr = self.write_input(&alloc, input.as_bytes())?;
+        let start = wasm_func.call(ptr, input.len() as u32)
+            .map_err(|e| trapped(name, e))?;
+        let memory = self.memory();
+        let len = MemoryLayout::read_len(&memory.view::<u8>());
+        let ret = if len == 0 {
+            Ok(String::new())
+        } else {
+            let (bytes_in, bytes_out) = self.marshalled.get();
+            self.marshalled.set((bytes_in, bytes_out + len as usize));
+            let ret = read_string(memory, start, len);
+            self.free_region(&free, start, len as usize)?;
+            ret
+        };
+        self.free_region(&free, ptr, input.len())?;
+        ret
+    }
+
+    /// Deserialize and free the output a plugin left at `start`,
+    /// it is deserialized straight out of memory without
+    /// being copied first
+    fn finish_call<O>(&self, format: SerializationFormat, free: &Func<(i32, u32), ()>, start: i32) -> Result<Option<O>, PluginError>
+    where O: DeserializeOwned {
+        let memory = self.memory();
+        let read = read_output_borrowed(memory, start, |bytes| {
+            (bytes.len(), decode_output::<O>(format, bytes))
+        })?;
+        // No output means there is nothing to read back or free
+        let (len, ret) = match read {
+            Some(read) => read,
+            None => return Ok(None),
+        };
+        let (bytes_in, bytes_out) = self.marshalled.get();
+        self.marshalled.set((bytes_in, bytes_out + len));
+        // The output belongs to us now, hand it back
+        // to the plugin so it can release it
+        self.free_region(free, start, len)?;
+        ret?.map(Some)
+            .map_err(PluginError::Plugin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        fs::File,
+        io::{
+            self,
+            Read,
+        },
+        rc::Rc,
+    };
+    use wasmer_runtime::{
+        compile,
+        instantiate,
+        types::MemoryDescriptor,
+    };
+    use std::time::Instant;
+
+    /// The example plugin, built with
+    /// `cargo build -p example-plugin --target wasm32-unknown-unknown`
+    static EXAMPLE_PLUGIN: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/target/wasm32-unknown-unknown/debug/example_plugin.wasm"
+    );
+
+    /// A plugin that works with its own `Config` type, built with
+    /// `cargo build -p example-config-plugin --target wasm32-unknown-unknown`
+    static CONFIG_PLUGIN: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/target/wasm32-unknown-unknown/debug/example_config_plugin.wasm"
+    );
+
+    fn example_plugin() -> PluginInstance {
+        load_plugin(EXAMPLE_PLUGIN)
+    }
+
+    fn load_plugin(path: &str) -> PluginInstance {
+        let mut buf = Vec::new();
+        File::open(path)
+            .expect("Failed to open example plugin, has it been built for wasm32?")
+            .read_to_end(&mut buf)
+            .expect("Failed to read example plugin");
+        let module = compile(&buf)
+            .expect("failed to compile wasm module");
+        PluginInstance::from_module(&module)
+            .expect("failed to instantiate wasm module")
+    }
+
+    /// Hands back its input as its output, with an
+    /// allocator that always points at address 16
+    static ECHO: &str = r#"
+        (module
+            (memory 1)
+            (func (export "__plugin_alloc") (param i32) (result i32)
+                i32.const 16)
+            (func (export "__plugin_free") (param i32 i32))
+            (func (export "_echo") (param i32 i32) (result i32)
+                i32.const 0
+                get_local 1
+                i32.store
+                get_local 0))
+    "#;
+
+    fn echo() -> PluginInstance {
+        let wasm = wabt::wat2wasm(ECHO).unwrap();
+        PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap())
+    }
+
+    #[test]
+    #[ignore]
+    fn replace_wasm() {
+        let plugin = example_plugin();
+        let chapter = "WASM is great, WASM is fast. ".repeat(1000);
+        let updated = plugin.call_string("_replace_wasm", &chapter).unwrap();
+        assert_eq!(updated, chapter.replace("WASM", "Wasm"));
+    }
+
+    #[test]
+    #[ignore]
+    fn multiply_round_trip() {
+        let plugin = example_plugin();
+        let pair = (2u8, String::from("attributed"));
+        let updated: (u8, String) = plugin.call("_multiply", &pair).unwrap();
+        assert_eq!(updated, (4, "attributed".repeat(4)));
+    }
+
+    #[test]
+    #[ignore]
+    fn reset_memory_example_plugin() {
+        let plugin = example_plugin();
+        // dlmalloc keeps its free list in freed chunks, so a reset
+        // touching one would break the calls after it
+        for i in 1..4u8 {
+            let pair = (i, String::from("attributed"));
+            let updated: (u8, String) = plugin.call("_multiply", &pair).unwrap();
+            assert_eq!(updated, (i * 2, "attributed".repeat(i as usize * 2)));
+            plugin.reset_memory();
+        }
+        let chapter = "WASM is great, WASM is fast. ".repeat(100);
+        assert_eq!(plugin.call_string("_replace_wasm", &chapter).unwrap(), chapter.replace("WASM", "Wasm"));
+    }
+
+    #[test]
+    #[ignore]
+    fn registry() {
+        let plugin = example_plugin();
+        let names: Vec<_> = plugin.registry().unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["_preprocess", "_wasm_to_wasm", "_replace_wasm", "_stamp_renderer", "_multiply"]);
+    }
+
+    #[derive(Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Config {
+        name: String,
+        retries: u8,
+        verbose: bool,
+    }
+
+    #[test]
+    #[ignore]
+    fn config_plugin() {
+        let plugin = load_plugin(CONFIG_PLUGIN);
+        let config = Config {
+            name: String::from("server-debug"),
+            retries: 20,
+            verbose: false,
+        };
+        let tuned: Config = plugin.call("_tune", &config).unwrap();
+        assert_eq!(tuned, Config { retries: 5, verbose: true, ..config });
+    }
+
+    #[test]
+    #[ignore]
+    fn alloc_does_not_overlap() {
+        let plugin = example_plugin();
+        let alloc = plugin.instance().func::<u32, i32>("__plugin_alloc").unwrap();
+        // Reserve a region for the plugin before we do
+        // anything and fill it with a marker
+        let live = alloc.call(64).unwrap() as usize;
+        let memory = plugin.instance().context().memory(0);
+        for cell in memory.view::<u8>()[live..live + 64].iter() {
+            cell.set(0xAA);
+        }
+        let pair = (2u8, String::from("attributed"));
+        let updated: (u8, String) = plugin.call("_multiply", &pair).unwrap();
+        assert_eq!(updated, (4, "attributed".repeat(4)));
+        // The live region should be untouched by the host's write
+        assert!(memory.view::<u8>()[live..live + 64].iter().all(|c| c.get() == 0xAA));
+    }
+
+    #[test]
+    #[ignore]
+    fn memory_stabilizes() {
+        let plugin = example_plugin();
+        let pair = (2u8, String::from("attributed"));
+        let run = || for _ in 0..100 {
+            let _: (u8, String) = plugin.call("_multiply", &pair).unwrap();
+        };
+        // Let the plugin's heap reach its working size
+        run();
+        let size = plugin.instance().context().memory(0).size();
+        // Since every buffer is freed, running again
+        // shouldn't need any more memory
+        run();
+        assert_eq!(plugin.instance().context().memory(0).size(), size);
+    }
+
+    #[test]
+    fn exports() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_shout") (param i32 i32) (result i32)
+                    i32.const 0)
+                (func (export "_whisper") (param i32 i32) (result i32)
+                    i32.const 0)
+                (func (export "helper")))
+        "#).unwrap();
+        let plugin = PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap());
+        assert_eq!(plugin.exports(), vec!["_shout", "_whisper"]);
+    }
+
+    /// A single page of memory that isn't attached to any module
+    fn memory() -> Memory {
+        Memory::new(MemoryDescriptor {
+            minimum: Pages(1),
+            maximum: None,
+            shared: false,
+        }).unwrap()
+    }
+
+    #[test]
+    fn write_then_read() {
+        let memory = memory();
+        MemoryLayout::write_len(&memory.view::<u8>(), 42);
+        write_input(&memory, 16, b"attributed").unwrap();
+        // Writing clears out the output length
+        assert_eq!(read_output(&memory, 16).unwrap(), None);
+        MemoryLayout::write_len(&memory.view::<u8>(), 10);
+        assert_eq!(read_output(&memory, 16).unwrap(), Some(b"attributed".to_vec()));
+    }
+
+    #[test]
+    fn payload_after_length() {
+        let memory = memory();
+        let start = MemoryLayout::data_offset();
+        write_input(&memory, start as i32, b"attributed").unwrap();
+        MemoryLayout::write_len(&memory.view::<u8>(), 10);
+        // Neither the length nor the payload clobbers the other
+        assert_eq!(MemoryLayout::read_len(&memory.view::<u8>()), 10);
+        assert_eq!(read_output(&memory, start as i32).unwrap(), Some(b"attributed".to_vec()));
+        // The payload starts right after the 4 length bytes
+        assert_eq!(memory.view::<u8>()[4].get(), b'a');
+    }
+
+    #[test]
+    fn read_borrowed() {
+        let memory = memory();
+        let pair = (2u8, String::from("attributed"));
+        let bytes = crate::revert_data(&pair);
+        write_input(&memory, 16, &bytes).unwrap();
+        MemoryLayout::write_len(&memory.view::<u8>(), bytes.len() as u32);
+        let back = read_output_borrowed(&memory, 16, |bytes| crate::try_convert_data::<(u8, String)>(bytes))
+            .unwrap()
+            .unwrap();
+        assert_eq!(back.unwrap(), pair);
+        MemoryLayout::write_len(&memory.view::<u8>(), 0);
+        assert_eq!(read_output_borrowed(&memory, 16, |_| panic!("nothing to read")).unwrap(), None::<()>);
+    }
+
+    #[test]
+    fn strings() {
+        let memory = memory();
+        assert_eq!(write_string(&memory, 16, "Wasm is fast").unwrap(), (16, 12));
+        assert_eq!(read_string(&memory, 16, 12).unwrap(), "Wasm is fast");
+        assert_eq!(read_string(&memory, 16, 4).unwrap(), "Wasm");
+        write_input(&memory, 16, &[0xff, 0xfe]).unwrap();
+        match read_string(&memory, 16, 2) {
+            Err(PluginError::Serde(_)) => (),
+            other => panic!("expected a serde error, found {:?}", other),
+        }
+        match read_string(&memory, WASM_PAGE_SIZE as i32 - 4, 8) {
+            Err(PluginError::Memory(_)) => (),
+            other => panic!("expected a memory error, found {:?}", other),
+        }
+    }
+
+    /// Touches `addr` before echoing its input, so it needs
+    /// memory to reach that far
+    fn touches(addr: usize) -> PluginInstance {
+        let wasm = wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_echo") (param i32 i32) (result i32)
+                    (i32.store8 (i32.const {}) (i32.const 1))
+                    i32.const 0
+                    get_local 1
+                    i32.store
+                    get_local 0))
+        "#, addr)).unwrap();
+        PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap())
+    }
+
+    #[test]
+    fn grows_out_of_bounds() {
+        let plugin = touches(WASM_PAGE_SIZE);
+        let back: String = plugin.call("_echo", Ok::<_, String>("attributed")).unwrap();
+        assert_eq!(back, "attributed");
+        assert_eq!(plugin.instance().context().memory(0).size(), Pages(2));
+        // Two pages short is given up on after one more page
+        let plugin = touches(WASM_PAGE_SIZE * 2);
+        match plugin.call::<_, String>("_echo", Ok::<_, String>("attributed")) {
+            Err(PluginError::Trap(msg)) => assert!(msg.starts_with("in _echo"), "{}", msg),
+            other => panic!("expected a trap, found {:?}", other),
+        }
+        assert_eq!(plugin.instance().context().memory(0).size(), Pages(2));
+        // Every copy of the input was handed back
+        assert!(plugin.scratch.borrow().is_empty());
+    }
+
+    #[test]
+    fn table_out_of_bounds_isnt_retried() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (type $echo (func (param i32 i32) (result i32)))
+                (table 1 anyfunc)
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_echo") (param i32 i32) (result i32)
+                    (call_indirect (type $echo) (get_local 0) (get_local 1) (i32.const 5))))
+        "#).unwrap();
+        let plugin = PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap());
+        match plugin.call::<_, String>("_echo", Ok::<_, String>("attributed")) {
+            Err(PluginError::Trap(msg)) => assert!(msg.starts_with("in _echo"), "{}", msg),
+            other => panic!("expected a trap, found {:?}", other),
+        }
+        // More memory can't help, so none was added
+        assert_eq!(plugin.instance().context().memory(0).size(), Pages(1));
+        assert!(plugin.scratch.borrow().is_empty());
+    }
+
+    #[test]
+    fn is_out_of_bounds() {
+        let trap = |msg: &str| PluginError::Trap(format!("in _echo, {}", msg));
+        assert!(super::is_out_of_bounds(&trap("memory out-of-bounds access")));
+        assert!(!super::is_out_of_bounds(&trap("table out-of-bounds access")));
+        assert!(!super::is_out_of_bounds(&trap("call_indirect index out of bounds")));
+        assert!(!super::is_out_of_bounds(&trap("unreachable")));
+        assert!(!super::is_out_of_bounds(&PluginError::Memory("out of bounds".to_string())));
+    }
+
+    #[test]
+    fn memory_index() {
+        let plugin = echo().with_memory_index(0).unwrap();
+        assert_eq!(plugin.call::<_, String>("_echo", Ok::<_, String>("attributed")).unwrap(), "attributed");
+        match echo().with_memory_index(1) {
+            Err(PluginError::Memory(msg)) => assert_eq!(msg, "there is no memory 1, the plugin has 1"),
+            other => panic!("expected a memory error, found {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let plugin = echo().with_max_payload_bytes(64);
+        let chapter = "WASM is great, WASM is fast. ".repeat(10);
+        match plugin.call::<_, String>("_echo", &chapter) {
+            Err(PluginError::TooLarge(msg)) => assert!(msg.ends_with("over the limit of 64 bytes"), "{}", msg),
+            other => panic!("expected too large, found {:?}", other),
+        }
+        // Nothing was copied over
+        assert_eq!(plugin.bytes_marshalled(), (0, 0));
+        assert!(plugin.call_string("_echo", "fits").is_ok());
+    }
+
+    #[test]
+    fn call_string() {
+        let plugin = echo();
+        let chapter = "WASM is great, WASM is fast. ".repeat(1000);
+        assert_eq!(plugin.call_string("_echo", &chapter).unwrap(), chapter);
+        // Only the string itself crosses the boundary
+        assert_eq!(plugin.bytes_marshalled(), (chapter.len(), chapter.len()));
+        assert_eq!(plugin.call_string("_echo", "").unwrap(), "");
+    }
+
+    #[test]
+    fn read_unchanged() {
+        let memory = memory();
+        MemoryLayout::write_len(&memory.view::<u8>(), MemoryLayout::UNCHANGED);
+        // The pointer isn't even looked at
+        assert_eq!(read_output(&memory, -1).unwrap(), None);
+    }
+
+    #[test]
+    fn write_grows_memory() {
+        let memory = memory();
+        write_input(&memory, WASM_PAGE_SIZE as i32 - 4, b"attributed").unwrap();
+        assert_eq!(memory.size(), Pages(2));
+        MemoryLayout::write_len(&memory.view::<u8>(), 10);
+        assert_eq!(read_output(&memory, WASM_PAGE_SIZE as i32 - 4).unwrap(), Some(b"attributed".to_vec()));
+    }
+
+    #[test]
+    fn read_out_of_bounds() {
+        let memory = memory();
+        MemoryLayout::write_len(&memory.view::<u8>(), 10);
+        assert!(read_output(&memory, WASM_PAGE_SIZE as i32).is_err());
+    }
+
+    #[test]
+    fn reset_memory() {
+        let plugin = echo();
+        let secret = String::from("a very long and very secret chapter");
+        let _: String = plugin.call("_echo", Ok::<_, String>(&secret)).unwrap();
+        plugin.reset_memory();
+        let view = plugin.instance().context().memory(0).view::<u8>();
+        assert!(view[..128].iter().all(|c| c.get() == 0));
+        // A shorter second call can't pick up the tail of the first
+        let back: String = plugin.call("_echo", Ok::<_, String>("short")).unwrap();
+        assert_eq!(back, "short");
+        let bytes = read_bytes(&view[..128]);
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+    }
+
+    /// Collects chunks after address 4096 and counts how
+    /// many it was fed, finishing echoes them all back
+    static CHUNKED_ECHO: &str = r#"
+        (module
+            (memory 1)
+            (global $end (mut i32) (i32.const 4096))
+            (global $chunks (mut i32) (i32.const 0))
+            (func (export "__plugin_alloc") (param i32) (result i32)
+                i32.const 16)
+            (func (export "__plugin_free") (param i32 i32))
+            (func (export "__plugin_feed_chunk") (param $ptr i32) (param $len i32)
+                (local $i i32)
+                (block $done
+                    (loop $copy
+                        (br_if $done (i32.ge_u (get_local $i) (get_local $len)))
+                        (i32.store8
+                            (i32.add (get_global $end) (get_local $i))
+                            (i32.load8_u (i32.add (get_local $ptr) (get_local $i))))
+                        (set_local $i (i32.add (get_local $i) (i32.const 1)))
+                        (br $copy)))
+                (set_global $end (i32.add (get_global $end) (get_local $len)))
+                (set_global $chunks (i32.add (get_global $chunks) (i32.const 1))))
+            (func (export "__plugin_finish_echo") (result i32)
+                (i32.store (i32.const 0) (i32.sub (get_global $end) (i32.const 4096)))
+                i32.const 4096)
+            (func (export "chunks") (result i32)
+                get_global $chunks))
+    "#;
+
+    #[test]
+    fn chunked() {
+        let wasm = wabt::wat2wasm(CHUNKED_ECHO).unwrap();
+        let plugin = PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap());
+        let chapters: Vec<String> = (0..10).map(|i| format!("Chapter {} is about WASM", i)).collect();
+        let input = Ok::<_, String>(&chapters);
+        let len = crate::revert_data(&input).len();
+        let back: Option<Vec<String>> = plugin.call_chunked("_echo", &input, (len + 2) / 3).unwrap();
+        assert_eq!(back, Some(chapters));
+        let chunks = plugin.instance().func::<(), i32>("chunks").unwrap().call().unwrap();
+        assert_eq!(chunks, 3);
+    }
+
+    #[test]
+    fn grows_memory() {
+        let plugin = echo();
+        // More than the single page the module starts with
+        let big = vec![7u8; WASM_PAGE_SIZE + 100];
+        let back: Vec<u8> = plugin.call("_echo", Ok::<_, String>(&big)).unwrap();
+        assert_eq!(back, big);
+        assert!(plugin.instance().context().memory(0).size() > Pages(1));
+    }
+
+    #[test]
+    fn three_tuple() {
+        let plugin = echo();
+        let triple = (2u8, String::from("attributed"), vec![1.5f32, -0.25]);
+        let back: (u8, String, Vec<f32>) = plugin.call("_echo", Ok::<_, String>(&triple)).unwrap();
+        assert_eq!(back, triple);
+        // Reading it back in a different order is an error, not a reshuffle
+        let swapped: Result<(String, u8, Vec<f32>), _> = plugin.call("_echo", Ok::<_, String>(&triple));
+        assert!(swapped.is_err());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let plugin = echo().with_format(SerializationFormat::Json);
+        let pair = (2u8, String::from("attributed"));
+        let back: (u8, String) = plugin.call("_echo", Ok::<_, String>(&pair)).unwrap();
+        assert_eq!(back, pair);
+    }
+
+    #[test]
+    fn bytes_marshalled() {
+        let plugin = echo();
+        assert_eq!(plugin.bytes_marshalled(), (0, 0));
+        let input = Ok::<_, String>("attributed");
+        let len = crate::revert_data(&input).len();
+        let _: String = plugin.call("_echo", &input).unwrap();
+        let _: String = plugin.call("_echo", &input).unwrap();
+        assert_eq!(plugin.bytes_marshalled(), (len * 2, len * 2));
+    }
+
+    /// A log sink the test can read back
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn host_log() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (import "env" "host_log" (func $log (param i32 i32)))
+                (memory 1)
+                (data (i32.const 32) "hello")
+                (data (i32.const 48) "bad \ff")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 64)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_log") (param i32 i32) (result i32)
+                    i32.const 32
+                    i32.const 5
+                    call $log
+                    i32.const 48
+                    i32.const 5
+                    call $log
+                    i32.const 0
+                    i32.const 0
+                    i32.store
+                    i32.const 0))
+        "#).unwrap();
+        let captured = Captured::default();
+        let plugin = PluginInstance::from_module(&compile(&wasm).unwrap())
+            .unwrap()
+            .with_log_name("log.wasm")
+            .with_log_sink(captured.clone());
+        let ret: Option<()> = plugin.call_optional("_log", ()).unwrap();
+        assert_eq!(ret, None);
+        let logged = String::from_utf8(captured.0.borrow().clone()).unwrap();
+        assert_eq!(logged, "log.wasm: hello\nlog.wasm: bad \u{FFFD}\n");
+    }
+
+    #[test]
+    fn wasi_plugin() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (import "wasi_unstable" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (memory 1)
+                (export "memory" (memory 0)))
+        "#).unwrap();
+        match PluginInstance::from_module(&compile(&wasm).unwrap()) {
+            Err(PluginError::Instantiate(msg)) => assert!(msg.contains("wasm32-wasi"), "{}", msg),
+            Err(e) => panic!("expected an instantiate error, found {}", e),
+            Ok(_) => panic!("a WASI plugin instantiated without WASI imports"),
+        }
+    }
+
+    #[test]
+    fn host_log_without_plugin_instance() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (import "env" "host_log" (func $log (param i32 i32)))
+                (memory 1)
+                (data (i32.const 32) "hello")
+                (func (export "_log")
+                    i32.const 32
+                    i32.const 5
+                    call $log))
+        "#).unwrap();
+        // Nothing has pointed the context's data at a log,
+        // so this goes to stderr instead of crashing
+        let instance = instantiate(&wasm, &host_imports()).unwrap();
+        instance.func::<(), ()>("_log").unwrap().call().unwrap();
+    }
+
+    #[test]
+    fn missing_export() {
+        let plugin = echo();
+        let view = plugin.instance().context().memory(0).view::<u8>();
+        MemoryLayout::write_len(&view, 42);
+        let ret: Result<u8, _> = plugin.call("_preprocess", 1u8);
+        assert_eq!(ret, Err(PluginError::MissingExport("_preprocess".to_string())));
+        // Nothing should have been written
+        assert_eq!(MemoryLayout::read_len(&view), 42);
+    }
+
+    /// A plugin that reports an output of `len`
+    /// bytes starting at `ptr`
+    fn bad_output(ptr: u32, len: u32) -> PluginInstance {
+        let wasm = wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_bad") (param i32 i32) (result i32)
+                    i32.const 0
+                    i32.const {}
+                    i32.store
+                    i32.const {}))
+        "#, len, ptr)).unwrap();
+        PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap())
+    }
+
+    #[test]
+    fn output_out_of_bounds() {
+        // Starts past the end of memory
+        let ret: Result<u8, _> = bad_output(WASM_PAGE_SIZE as u32 * 2, 10).call("_bad", 1u8);
+        match ret {
+            Err(PluginError::Trap(msg)) => assert!(msg.contains("outside of memory")),
+            other => panic!("expected a trap, found {:?}", other),
+        }
+        // Starts inside memory but runs off the end
+        let ret: Result<u8, _> = bad_output(16, WASM_PAGE_SIZE as u32).call("_bad", 1u8);
+        match ret {
+            Err(PluginError::Trap(msg)) => assert!(msg.contains("outside of memory")),
+            other => panic!("expected a trap, found {:?}", other),
+        }
+    }
+
+    fn host_sleep(_ctx: &mut Ctx, ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+
+    /// Sleeps for a second through the host
+    /// before writing a length of 0
+    fn sleeper() -> Result<PluginInstance, PluginError> {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (import "env" "host_sleep" (func $sleep (param i32)))
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_sleep") (param i32 i32) (result i32)
+                    i32.const 1000
+                    call $sleep
+                    i32.const 0
+                    i32.const 0
+                    i32.store
+                    i32.const 0))
+        "#).unwrap();
+        let imports = imports! {
+            "env" => {
+                "host_sleep" => func!(host_sleep),
+            },
+        };
+        let instance = instantiate(&wasm, &imports)
+            .map_err(|e| PluginError::Instantiate(e.to_string()))?;
+        Ok(PluginInstance::new(instance))
+    }
+
+    #[test]
+    fn timeout() {
+        let start = Instant::now();
+        let ret: Result<Option<()>, _> = PluginInstance::spawn_call(sleeper, "_sleep", (), Some(Duration::from_millis(50)));
+        match ret {
+            Err(PluginError::Timeout(msg)) => assert!(msg.starts_with("_sleep")),
+            other => panic!("expected a timeout, found {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        // Without a deadline it runs to the end
+        let ret: Option<()> = PluginInstance::spawn_call(sleeper, "_sleep", (), None).unwrap();
+        assert_eq!(ret, None);
+    }
+
+    #[test]
+    fn abi_version() {
+        let abi = |version: u32| {
+            let wasm = wabt::wat2wasm(format!(r#"
+                (module
+                    (memory 1)
+                    (func (export "__abi_version") (result i32)
+                        i32.const {}))
+            "#, version)).unwrap();
+            PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap())
+        };
+        assert_eq!(abi(ABI_VERSION).check_abi(), Ok(()));
+        match abi(ABI_VERSION + 1).check_abi() {
+            Err(PluginError::AbiMismatch(_)) => (),
+            other => panic!("expected an ABI mismatch, found {:?}", other),
+        }
+        // Nothing exported is taken to be the current version
+        assert_eq!(echo().check_abi(), Ok(()));
+    }
+
+    #[test]
+    fn healthy() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "_health") (result i32)
+                    i32.const 0))
+        "#).unwrap();
+        let plugin = PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap());
+        assert_eq!(plugin.healthy(), Ok(false));
+        assert_eq!(echo().healthy(), Ok(true));
+    }
+
+    #[test]
+    fn supports() {
+        // Only supports renderers whose name starts with an h
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_supports") (param i32 i32) (result i32)
+                    get_local 0
+                    i32.load8_u
+                    i32.const 104
+                    i32.eq))
+        "#).unwrap();
+        let plugin = PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap());
+        assert_eq!(plugin.supports("html"), Ok(true));
+        assert_eq!(plugin.supports("epub"), Ok(false));
+        // No export means everything is supported
+        assert_eq!(echo().supports("epub"), Ok(true));
+    }
+
+    #[test]
+    fn strip_type_paths() {
+        assert_eq!(
+            super::strip_type_paths("(mdbook::preprocess::PreprocessorContext, mdbook::book::Book)"),
+            "(PreprocessorContext,Book)",
+        );
+        assert_eq!(super::strip_type_paths("(::mdbook::preprocess::PreprocessorContext,Book)"), "(PreprocessorContext,Book)");
+        assert_eq!(super::strip_type_paths("&mut alloc::vec::Vec<alloc::string::String>"), "&mutVec<String>");
+    }
+
+    #[test]
+    fn type_mismatch() {
+        // Expects a `u8`, echoing whatever it is sent
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (data (i32.const 2048) "u8")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "__input_type_of_echo") (result i32)
+                    i32.const 0
+                    i32.const 2
+                    i32.store
+                    i32.const 2048)
+                (func (export "_echo") (param i32 i32) (result i32)
+                    i32.const 0
+                    get_local 1
+                    i32.store
+                    get_local 0))
+        "#).unwrap();
+        let plugin = PluginInstance::new(instantiate(&wasm, &imports!{}).unwrap())
+            .with_type_checks(true);
+        assert_eq!(plugin.check_input::<u8>("_echo"), Ok(()));
+        match plugin.check_input::<&u8>("_echo") {
+            Err(PluginError::TypeMismatch(msg)) => assert_eq!(msg, "_echo expects u8 but was sent &u8"),
+            other => panic!("expected a type mismatch, found {:?}", other),
+        }
+        match plugin.call::<_, String>("_echo", "attributed") {
+            Err(PluginError::TypeMismatch(msg)) => assert_eq!(msg, "_echo expects u8 but was sent &str"),
+            other => panic!("expected a type mismatch, found {:?}", other),
+        }
+        // Without the export there is nothing to check against
+        assert_eq!(echo().check_input::<String>("_echo"), Ok(()));
+    }
+
+    #[test]
+    fn plugin_error() {
+        let plugin = echo();
+        let ret: Result<(u8, String), _> = plugin.call("_echo", Err::<(), _>("nope"));
+        assert_eq!(ret, Err(PluginError::Plugin("nope".to_string())));
+    }
+}
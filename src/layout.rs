@@ -0,0 +1,126 @@
+// ./src/layout.rs
+use std::cell::Cell;
+
+/// Where the plugin ABI keeps things in linear memory.
+///
+/// A plugin writes the length of its output into the 4 bytes
+/// starting at `LEN_OFFSET` and returns a pointer to the output
+/// itself, the host reads that length back out of memory.
+///
+/// The length is always little-endian, the same as wasm's own
+/// `i32.store`, so it reads the same whatever the host is built for
+pub struct MemoryLayout;
+
+impl MemoryLayout {
+    /// The address of the 4 byte length of a plugin's output,
+    /// the start of memory so nothing is left unused before it
+    pub const LEN_OFFSET: usize = 0;
+    /// The number of bytes used for the length
+    pub const LEN_SIZE: usize = 4;
+    /// The length a plugin writes when it left its input
+    /// unchanged, the host keeps its own copy instead of
+    /// reading anything back
+    pub const UNCHANGED: u32 = 0xFFFF_FFFF;
+
+    /// The first address after the length, the lowest
+    /// address an input or output can start at without
+    /// overlapping the length slot
+    pub const fn data_offset() -> usize {
+        Self::LEN_OFFSET + Self::LEN_SIZE
+    }
+
+    /// The bytes `len` is stored as in the length slot
+    pub fn encode_len(len: u32) -> [u8; 4] {
+        len.to_le_bytes()
+    }
+
+    /// The length stored as `bytes` in the length slot
+    pub fn decode_len(bytes: [u8; 4]) -> u32 {
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Write `len` into the length slot of `mem`, which
+    /// should be a view of memory starting at address 0.
+    /// Any bytes of the slot past the end of `mem` are skipped
+    pub fn write_len(mem: &[Cell<u8>], len: u32) {
+        for (i, byte) in Self::encode_len(len).iter().enumerate() {
+            if let Some(cell) = mem.get(Self::LEN_OFFSET + i) {
+                cell.set(*byte);
+            }
+        }
+    }
+
+    /// Read the length slot out of `mem`, which should be
+    /// a view of memory starting at address 0. Any bytes
+    /// of the slot past the end of `mem` are read as 0
+    pub fn read_len(mem: &[Cell<u8>]) -> u32 {
+        let mut bytes = [0u8; Self::LEN_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = mem.get(Self::LEN_OFFSET + i)
+                .map(|c| c.get())
+                .unwrap_or(0);
+        }
+        Self::decode_len(bytes)
+    }
+
+    /// Write `len` into the length slot of the current module's
+    /// memory, this is only meaningful from inside a plugin.
+    ///
+    /// The slot is at the null address, which is never part of
+    /// a rust allocation, so it is written with a volatile write
+    /// the compiler can't assume away like an ordinary one
+    ///
+    /// # Safety
+    /// The caller must be running inside a wasm module
+    /// where the length slot is reserved for this ABI
+    pub unsafe fn write_len_raw(len: u32) {
+        ::std::ptr::write_volatile(Self::LEN_OFFSET as *mut [u8; 4], Self::encode_len(len));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn memory(len: usize) -> Vec<Cell<u8>> {
+        (0..len).map(|_| Cell::new(0xff)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mem = memory(64);
+        MemoryLayout::write_len(&mem, 123_456);
+        assert_eq!(MemoryLayout::read_len(&mem), 123_456);
+    }
+
+    #[test]
+    fn little_endian() {
+        let mem = memory(64);
+        MemoryLayout::write_len(&mem, 0x0102_0304);
+        let slot: Vec<u8> = mem[MemoryLayout::LEN_OFFSET..MemoryLayout::data_offset()]
+            .iter()
+            .map(|c| c.get())
+            .collect();
+        assert_eq!(slot, vec![4, 3, 2, 1]);
+        assert_eq!(MemoryLayout::decode_len([4, 3, 2, 1]), 0x0102_0304);
+    }
+
+    #[test]
+    fn length_then_data() {
+        // The length is bytes 0..4 and data starts at 4
+        assert_eq!(MemoryLayout::LEN_OFFSET, 0);
+        assert_eq!(MemoryLayout::data_offset(), 4);
+        let mem = memory(MemoryLayout::data_offset());
+        MemoryLayout::write_len(&mem, 0);
+        assert!(mem.iter().all(|c| c.get() == 0));
+    }
+
+    #[test]
+    fn zero_length() {
+        let mem = memory(64);
+        MemoryLayout::write_len(&mem, 0);
+        assert_eq!(MemoryLayout::read_len(&mem), 0);
+        // Nothing after the slot should be touched
+        assert_eq!(mem[MemoryLayout::data_offset()].get(), 0xff);
+    }
+}
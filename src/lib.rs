@@ -1,15 +1,895 @@
 // ./src/lib.rs
-use serde::{Serialize, Deserialize};
-use bincode::{serialize, deserialize};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use bincode::Options;
+use std::{
+    any::Any,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    panic,
+    path::PathBuf,
+    sync::Once,
+};
 
-pub use example_macro::plugin_helper;
+pub use example_macro::{plugin_helper, plugin_main, plugin_registry, plugin_roundtrip_test};
 
+mod error;
+mod executor;
+mod format;
+#[cfg(feature = "host")]
+mod host;
+mod layout;
+mod memory;
+#[cfg(feature = "native")]
+mod native;
+#[cfg(feature = "host")]
+mod plugin_host;
+mod registry;
+pub use error::PluginError;
+pub use executor::block_on;
+pub use format::{FormatError, SerializationFormat};
+#[cfg(feature = "host")]
+pub use host::{host_imports, read_output, read_output_borrowed, read_string, write_input, write_string, PluginInstance};
+pub use layout::MemoryLayout;
+pub use memory::{read_bytes, write_bytes};
+#[cfg(feature = "native")]
+pub use native::NativePlugin;
+#[cfg(feature = "host")]
+pub use plugin_host::{PluginHost, PluginHostBuilder};
+pub use registry::PluginExport;
+
+/// Reserve `len` bytes of memory for the host to write
+/// into, returning a pointer to the start of that region.
+///
+/// This lives here instead of being emitted by `plugin_helper`
+/// since a plugin may mark more than one function and
+/// each would otherwise define its own `__plugin_alloc`
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn __plugin_alloc(len: u32) -> i32 {
+    // A boxed slice has exactly `len` bytes of capacity
+    // which is what `__plugin_free` expects
+    let buf = vec![0u8; len as usize].into_boxed_slice();
+    // The host now owns this region
+    Box::into_raw(buf) as *mut u8 as i32
+}
+
+/// Release a region handed to the host, either by
+/// `__plugin_alloc` or as the output of a `plugin_helper`
+/// function. The host must call this exactly once per
+/// region with the same length it was handed
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn __plugin_free(ptr: i32, len: u32) {
+    unsafe {
+        drop(Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize));
+    }
+}
+
+/// The `ABI_VERSION` this plugin was built
+/// with, checked by the host before any call
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn __abi_version() -> u32 {
+    ABI_VERSION
+}
+
+#[cfg(target_arch = "wasm32")]
+extern "C" {
+    fn host_log(ptr: i32, len: u32);
+}
+
+/// Write `msg` to the host's stderr, outside of
+/// wasm this goes straight to stderr
+pub fn log(msg: &str) {
+    #[cfg(target_arch = "wasm32")]
+    unsafe {
+        host_log(msg.as_ptr() as i32, msg.len() as u32);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", msg);
+}
+
+/// Log a message from a plugin, this takes
+/// the same arguments as `format!`
+#[macro_export]
+macro_rules! plugin_log {
+    ($($arg:tt)*) => {
+        $crate::log(&format!($($arg)*))
+    };
+}
+
+/// Every bincode payload starts with this, a magic number
+/// followed by a version that changes whenever the way we
+/// encode data does, so a plugin and host built differently
+/// get an error instead of garbage
+pub const WIRE_HEADER: [u8; 4] = [b'w', b'p', b'e', 1];
+
+/// The version of the memory protocol, where lengths are
+/// written and how payloads are laid out, this crate speaks.
+/// A host refuses to call a plugin built against another one
+pub const ABI_VERSION: u32 = 2;
+
+/// What a plugin function can return when it may not
+/// have anything to change, returning `Unchanged` lets the
+/// host skip reading the output back entirely
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginOutput<T> {
+    Changed(T),
+    Unchanged,
+}
+
+/// Where a chapter is in a book, the index of the section it
+/// is in followed by its index in each level of sub items
+pub type ChapterPath = Vec<usize>;
+
+/// The new content of only the chapters a plugin changed,
+/// sent as a `PluginReturn`'s `delta` and applied by the
+/// host to its own copy of the book
+pub type BookDelta = Vec<(ChapterPath, String)>;
+
+/// Everything a preprocessor can hand back, a function returning
+/// this instead of its book can set any mix of the fields. `B` is
+/// the book and `C` the context, mdBook's `Book` and
+/// `PreprocessorContext` for an mdBook plugin.
+///
+/// Every field is always sent, so this works the same for
+/// formats that can't skip fields like bincode
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginReturn<B, C> {
+    /// The updated book, `None` keeps the host's copy
+    pub book: Option<B>,
+    /// Only the chapters that changed, applied
+    /// to the book after it is updated
+    pub delta: Option<BookDelta>,
+    /// The context the plugins after this one should see
+    pub context: Option<C>,
+    /// New files to write under the book's src
+    pub files: Vec<(PathBuf, String)>,
+    /// Warnings for the host to show
+    pub warnings: Vec<String>,
+    /// Every plugin should run again over the updated book
+    pub rerun: bool,
+}
+
+impl<B, C> PluginReturn<B, C> {
+    /// A return of only `book`
+    pub fn new(book: B) -> Self {
+        Self {
+            book: Some(book),
+            ..Self::default()
+        }
+    }
+}
+
+impl<B, C> Default for PluginReturn<B, C> {
+    fn default() -> Self {
+        Self {
+            book: None,
+            delta: None,
+            context: None,
+            files: Vec::new(),
+            warnings: Vec::new(),
+            rerun: false,
+        }
+    }
+}
+
+/// Used by `plugin_helper` so an argument whose type
+/// can't be deserialized is reported at the argument
+#[doc(hidden)]
+pub fn input_must_implement_deserialize<T: DeserializeOwned>() {}
+
+/// The same for `plugin_helper(borrow)`, where an
+/// argument can borrow from the input unless the
+/// `compress` feature is on
+#[doc(hidden)]
+pub fn input_must_implement_deserialize_borrowed<'de, T: FromPayload<'de>>() {}
+
+thread_local! {
+    static CHUNKS: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    static HOST_ENV: RefCell<Option<serde_json::Value>> = RefCell::new(None);
+    static HOST_SEED: Cell<Option<u64>> = Cell::new(None);
+    static HOST_ARGS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    static PLUGIN_CONTEXT: RefCell<Option<Box<dyn Any>>> = RefCell::new(None);
+    static PANIC_MESSAGE: RefCell<String> = RefCell::new(String::new());
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Keep the message of any panic where the host can find it
+/// with `__plugin_panic_message`, `plugin_helper` calls this at
+/// the start of every export so a plugin never has to.
+///
+/// The previous hook still runs after the message is kept. Any
+/// message from an earlier call is cleared so a later trap isn't
+/// reported as that panic
+pub fn install_panic_hook() {
+    PANIC_MESSAGE.with(|m| m.borrow_mut().clear());
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let payload = info.payload();
+            let msg = payload.downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("unknown panic"));
+            PANIC_MESSAGE.with(|m| *m.borrow_mut() = msg);
+            previous(info);
+        }));
+    });
+}
+
+/// Called by the host after a plugin traps, returns a pointer
+/// to the message of the last panic with its length in the
+/// length slot. The length is 0 if the plugin never panicked
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn __plugin_panic_message() -> i32 {
+    PANIC_MESSAGE.with(|m| {
+        let m = m.borrow();
+        unsafe { MemoryLayout::write_len_raw(m.len() as u32) };
+        m.as_ptr() as i32
+    })
+}
+
+#[doc(hidden)]
+pub fn panic_message() -> String {
+    PANIC_MESSAGE.with(|m| m.borrow().clone())
+}
+
+/// Called by the host with the environment it was built
+/// with, as JSON, when the plugin is instantiated
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn _set_env(ptr: i32, len: u32) {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(ptr as *const u8, len as usize)
+    };
+    set_host_env(bytes);
+}
+
+#[doc(hidden)]
+pub fn set_host_env(bytes: &[u8]) {
+    HOST_ENV.with(|env| *env.borrow_mut() = serde_json::from_slice(bytes).ok());
+}
+
+/// The values the host passed in with `PluginHostBuilder::with_env`,
+/// `None` if it didn't pass any
+pub fn host_env() -> Option<serde_json::Value> {
+    HOST_ENV.with(|env| env.borrow().clone())
+}
+
+/// Called by the host with the arguments it was built
+/// with, as a JSON array, when the plugin is instantiated
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn _set_args(ptr: i32, len: u32) {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(ptr as *const u8, len as usize)
+    };
+    set_host_args(bytes);
+}
+
+#[doc(hidden)]
+pub fn set_host_args(bytes: &[u8]) {
+    HOST_ARGS.with(|args| *args.borrow_mut() = serde_json::from_slice(bytes).unwrap_or_default());
+}
+
+/// The arguments the host passed in with `PluginHostBuilder::with_args`,
+/// such as the ones after `--` on the runner's command line. Empty if
+/// it didn't pass any
+pub fn host_args() -> Vec<String> {
+    HOST_ARGS.with(|args| args.borrow().clone())
+}
+
+/// Called by the host with the seed it was built with
+/// when the plugin is instantiated
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn _set_seed(seed: u64) {
+    set_host_seed(seed);
+}
+
+#[doc(hidden)]
+pub fn set_host_seed(seed: u64) {
+    HOST_SEED.with(|s| s.set(Some(seed)));
+}
+
+/// The seed the host passed in with `PluginHostBuilder::with_seed`,
+/// a plugin seeds any randomness with this so the same build
+/// gives the same output. `None` if it didn't pass one
+pub fn host_seed() -> Option<u64> {
+    HOST_SEED.with(|s| s.get())
+}
+
+/// Used by `plugin_helper(plugin_context)` to keep the context
+/// sent with a call for its `plugin_context()`, replacing the
+/// one kept for the call before
+#[doc(hidden)]
+pub fn set_plugin_context<T: Any>(ctx: T) {
+    PLUGIN_CONTEXT.with(|c| *c.borrow_mut() = Some(Box::new(ctx)));
+}
+
+/// Run `f` with the context kept by `set_plugin_context`,
+/// `None` if there isn't one or it isn't a `T`
+#[doc(hidden)]
+pub fn with_plugin_context<T: Any, R>(f: impl FnOnce(&T) -> R) -> Option<R> {
+    PLUGIN_CONTEXT.with(|c| c.borrow().as_ref()
+        .and_then(|ctx| ctx.downcast_ref())
+        .map(f))
+}
+
+/// Add a chunk of input to the buffer a host is filling,
+/// this is how an input too big to copy into memory in one
+/// go is sent. Once every chunk is in the host calls
+/// `__plugin_finish` followed by the export's name, which
+/// runs the export on everything fed in so far
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub fn __plugin_feed_chunk(ptr: i32, len: u32) {
+    let chunk = unsafe {
+        std::slice::from_raw_parts(ptr as *const u8, len as usize)
+    };
+    CHUNKS.with(|chunks| chunks.borrow_mut().extend_from_slice(chunk));
+}
+
+/// Take everything fed in with `__plugin_feed_chunk`
+#[doc(hidden)]
+pub fn take_chunks() -> Vec<u8> {
+    CHUNKS.with(|chunks| std::mem::replace(&mut *chunks.borrow_mut(), Vec::new()))
+}
+
+/// The bincode settings every payload is written and read with,
+/// fixed size little endian integers. These are spelled out
+/// instead of relying on bincode's defaults so a host and plugin
+/// built against different versions of bincode still agree
+pub fn bincode_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+/// Deserialize a value from the bytes provided,
+/// panicking if they are malformed
 pub fn convert_data<'a, D>(bytes: &'a [u8]) -> D 
-where D: Deserialize<'a> {
-    deserialize(bytes).expect("Failed to deserialize bytes")
+where D: FromPayload<'a> {
+    try_convert_data(bytes).expect("Failed to deserialize bytes")
+}
+
+/// Deserialize a value from the bytes provided
+pub fn try_convert_data<'a, D>(bytes: &'a [u8]) -> Result<D, bincode::Error>
+where D: FromPayload<'a> {
+    #[cfg(feature = "compress")]
+    let bytes = &unpack::<bincode::Error>(bytes)?;
+    bincode_options().deserialize(wire_body(bytes)?)
+}
+
+/// The bincode between the wire header and checksum of `bytes`
+fn wire_body(bytes: &[u8]) -> Result<&[u8], bincode::Error> {
+    let len = WIRE_HEADER.len();
+    if bytes.len() < len || bytes[..len] != WIRE_HEADER {
+        let found = &bytes[..len.min(bytes.len())];
+        let msg = format!("expected wire header {:?}, found {:?}", WIRE_HEADER, found);
+        return Err(Box::new(bincode::ErrorKind::Custom(msg)));
+    }
+    let bytes = check_payload(bytes)
+        .map_err(|e| Box::new(bincode::ErrorKind::Custom(e.to_string())))?;
+    Ok(&bytes[len..])
+}
+
+/// Deserialize a value that doesn't borrow from `bytes`,
+/// panicking if they are malformed. This is what to use
+/// when `bytes` won't be around for long, like a view
+/// into a plugin's memory
+pub fn convert_data_owned<D>(bytes: &[u8]) -> D
+where D: DeserializeOwned {
+    try_convert_data_owned(bytes).expect("Failed to deserialize bytes")
+}
+
+/// Deserialize a value that doesn't borrow from `bytes`, when
+/// that fails the error says how far into `bytes` it got
+pub fn try_convert_data_owned<D>(bytes: &[u8]) -> Result<D, bincode::Error>
+where D: DeserializeOwned {
+    #[cfg(feature = "compress")]
+    let bytes = &unpack::<bincode::Error>(bytes)?;
+    let body = wire_body(bytes)?;
+    // Reading from a slice leaves it at the
+    // bytes that were never deserialized
+    let mut rest = body;
+    bincode_options().deserialize_from(&mut rest).map_err(|e| {
+        let offset = WIRE_HEADER.len() + body.len() - rest.len();
+        Box::new(bincode::ErrorKind::Custom(format!("{} at byte {} of {}", e, offset, bytes.len())))
+    })
 }
 
+/// The size of the CRC32 at the end of every bincode payload
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Check the CRC32 that `revert_data` puts at the end of a
+/// payload, returning everything before it
+pub fn check_payload(bytes: &[u8]) -> Result<&[u8], PluginError> {
+    if bytes.len() < CHECKSUM_SIZE {
+        return Err(PluginError::Corrupt(format!("payload of {} bytes is too short for a checksum", bytes.len())));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    let mut expected = [0u8; CHECKSUM_SIZE];
+    expected.copy_from_slice(checksum);
+    if crc32fast::hash(body) != u32::from_le_bytes(expected) {
+        return Err(PluginError::Corrupt(format!("checksum mismatch over {} bytes, the payload was truncated or overwritten", body.len())));
+    }
+    Ok(body)
+}
+
+fn append_checksum(bytes: &mut Vec<u8>) {
+    let checksum = crc32fast::hash(bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+}
+
+/// Serialize the value provided, panicking
+/// if it cannot be serialized
 pub fn revert_data<S>(s: S) -> Vec<u8> 
 where S: Serialize {
-    serialize(&s).expect("Failed to serialize data")
+    try_revert_data(s).expect("Failed to serialize data")
+}
+
+/// Serialize the value provided
+pub fn try_revert_data<S>(s: S) -> Result<Vec<u8>, bincode::Error>
+where S: Serialize {
+    let mut bytes = WIRE_HEADER.to_vec();
+    bytes.extend(bincode_options().serialize(&s)?);
+    append_checksum(&mut bytes);
+    pack(bytes)
+}
+
+/// Serialize the value provided into `buf`, panicking
+/// if it cannot be serialized. `buf` is cleared first
+pub fn revert_data_into<S>(s: &S, buf: &mut Vec<u8>)
+where S: Serialize {
+    try_revert_data_into(s, buf).expect("Failed to serialize data")
+}
+
+/// Serialize the value provided into `buf`, clearing
+/// it first but keeping its capacity
+pub fn try_revert_data_into<S>(s: &S, buf: &mut Vec<u8>) -> Result<(), bincode::Error>
+where S: Serialize {
+    buf.clear();
+    buf.extend_from_slice(&WIRE_HEADER);
+    bincode_options().serialize_into(&mut *buf, s)?;
+    append_checksum(buf);
+    pack_into(buf)
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// Run `f` with a buffer that is kept around between
+/// calls, the `_into` functions clear it before writing
+/// so whatever was left from the last call is discarded
+pub fn with_scratch<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    SCRATCH.with(|buf| f(&mut buf.borrow_mut()))
+}
+
+/// Deserialize a value from JSON bytes,
+/// panicking if they are malformed
+pub fn convert_data_json<'a, D>(bytes: &'a [u8]) -> D
+where D: FromPayload<'a> {
+    try_convert_data_json(bytes).expect("Failed to deserialize json")
+}
+
+/// Deserialize a value from JSON bytes
+pub fn try_convert_data_json<'a, D>(bytes: &'a [u8]) -> Result<D, serde_json::Error>
+where D: FromPayload<'a> {
+    #[cfg(feature = "compress")]
+    let bytes = &unpack::<serde_json::Error>(bytes)?;
+    serde_json::from_slice(bytes)
+}
+
+/// Serialize the value provided as JSON, panicking
+/// if it cannot be serialized
+pub fn revert_data_json<S>(s: S) -> Vec<u8>
+where S: Serialize {
+    try_revert_data_json(s).expect("Failed to serialize json")
+}
+
+/// Serialize the value provided as JSON
+pub fn try_revert_data_json<S>(s: S) -> Result<Vec<u8>, serde_json::Error>
+where S: Serialize {
+    pack(serde_json::to_vec(&s)?)
+}
+
+/// Serialize the value provided as JSON into `buf`,
+/// panicking if it cannot be serialized. `buf` is
+/// cleared first
+pub fn revert_data_json_into<S>(s: &S, buf: &mut Vec<u8>)
+where S: Serialize {
+    buf.clear();
+    serde_json::to_writer(&mut *buf, s).expect("Failed to serialize json");
+    pack_into::<serde_json::Error>(buf).expect("Failed to compress json")
+}
+
+/// Deserialize a value from MessagePack bytes,
+/// panicking if they are malformed
+#[cfg(feature = "messagepack")]
+pub fn convert_data_msgpack<'a, D>(bytes: &'a [u8]) -> D
+where D: FromPayload<'a> {
+    try_convert_data_msgpack(bytes).expect("Failed to deserialize messagepack")
+}
+
+/// Deserialize a value from MessagePack bytes
+#[cfg(feature = "messagepack")]
+pub fn try_convert_data_msgpack<'a, D>(bytes: &'a [u8]) -> Result<D, rmp_serde::decode::Error>
+where D: FromPayload<'a> {
+    #[cfg(feature = "compress")]
+    let bytes = &unpack::<rmp_serde::decode::Error>(bytes)?;
+    rmp_serde::from_slice(bytes)
+}
+
+/// Serialize the value provided as MessagePack,
+/// panicking if it cannot be serialized
+#[cfg(feature = "messagepack")]
+pub fn revert_data_msgpack<S>(s: S) -> Vec<u8>
+where S: Serialize {
+    try_revert_data_msgpack(s).expect("Failed to serialize messagepack")
+}
+
+/// Serialize the value provided as MessagePack
+#[cfg(feature = "messagepack")]
+pub fn try_revert_data_msgpack<S>(s: S) -> Result<Vec<u8>, rmp_serde::encode::Error>
+where S: Serialize {
+    pack(rmp_serde::to_vec(&s)?)
+}
+
+/// Serialize the value provided as MessagePack into
+/// `buf`, panicking if it cannot be serialized. `buf`
+/// is cleared first
+#[cfg(feature = "messagepack")]
+pub fn revert_data_msgpack_into<S>(s: &S, buf: &mut Vec<u8>)
+where S: Serialize {
+    buf.clear();
+    rmp_serde::encode::write(&mut *buf, s).expect("Failed to serialize messagepack");
+    pack_into::<rmp_serde::encode::Error>(buf).expect("Failed to compress messagepack")
+}
+
+/// Deserialize a value from CBOR bytes,
+/// panicking if they are malformed
+#[cfg(feature = "cbor")]
+pub fn convert_data_cbor<'a, D>(bytes: &'a [u8]) -> D
+where D: FromPayload<'a> {
+    try_convert_data_cbor(bytes).expect("Failed to deserialize cbor")
+}
+
+/// Deserialize a value from CBOR bytes
+#[cfg(feature = "cbor")]
+pub fn try_convert_data_cbor<'a, D>(bytes: &'a [u8]) -> Result<D, serde_cbor::Error>
+where D: FromPayload<'a> {
+    #[cfg(feature = "compress")]
+    let bytes = &unpack::<serde_cbor::Error>(bytes)?;
+    serde_cbor::from_slice(bytes)
+}
+
+/// Serialize the value provided as CBOR,
+/// panicking if it cannot be serialized
+#[cfg(feature = "cbor")]
+pub fn revert_data_cbor<S>(s: S) -> Vec<u8>
+where S: Serialize {
+    try_revert_data_cbor(s).expect("Failed to serialize cbor")
+}
+
+/// Serialize the value provided as CBOR
+#[cfg(feature = "cbor")]
+pub fn try_revert_data_cbor<S>(s: S) -> Result<Vec<u8>, serde_cbor::Error>
+where S: Serialize {
+    pack(serde_cbor::to_vec(&s)?)
+}
+
+/// Serialize the value provided as CBOR into `buf`,
+/// panicking if it cannot be serialized. `buf` is
+/// cleared first
+#[cfg(feature = "cbor")]
+pub fn revert_data_cbor_into<S>(s: &S, buf: &mut Vec<u8>)
+where S: Serialize {
+    buf.clear();
+    serde_cbor::to_writer(&mut *buf, s).expect("Failed to serialize cbor");
+    pack_into::<serde_cbor::Error>(buf).expect("Failed to compress cbor")
+}
+
+/// What the borrowing `convert_data` functions can deserialize.
+///
+/// With the `compress` feature every payload is decompressed
+/// into a buffer of its own before it is read, so nothing can
+/// borrow from it and this is only `DeserializeOwned`. Without
+/// it this is any `Deserialize<'a>`. Both a plugin and its host
+/// need to be built with the same setting for this feature
+#[cfg(feature = "compress")]
+pub trait FromPayload<'a>: DeserializeOwned {}
+#[cfg(feature = "compress")]
+impl<'a, D: DeserializeOwned> FromPayload<'a> for D {}
+#[cfg(not(feature = "compress"))]
+pub trait FromPayload<'a>: Deserialize<'a> {}
+#[cfg(not(feature = "compress"))]
+impl<'a, D: Deserialize<'a>> FromPayload<'a> for D {}
+
+/// Compress a payload on its way across the wasm boundary,
+/// every `revert_data` function ends with this
+#[cfg(feature = "compress")]
+fn pack<E: serde::ser::Error>(bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+    zstd::encode_all(bytes.as_slice(), 0).map_err(E::custom)
+}
+
+#[cfg(not(feature = "compress"))]
+fn pack<E: serde::ser::Error>(bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+    Ok(bytes)
+}
+
+/// `pack` in place, `buf` keeps its capacity
+#[cfg(feature = "compress")]
+fn pack_into<E: serde::ser::Error>(buf: &mut Vec<u8>) -> Result<(), E> {
+    let packed = zstd::encode_all(buf.as_slice(), 0).map_err(E::custom)?;
+    buf.clear();
+    buf.extend_from_slice(&packed);
+    Ok(())
+}
+
+#[cfg(not(feature = "compress"))]
+fn pack_into<E: serde::ser::Error>(_: &mut Vec<u8>) -> Result<(), E> {
+    Ok(())
+}
+
+/// Undo `pack`, every `convert_data` function starts with this
+#[cfg(feature = "compress")]
+fn unpack<E: serde::de::Error>(bytes: &[u8]) -> Result<Vec<u8>, E> {
+    zstd::decode_all(bytes).map_err(E::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let pair = (2u8, String::from("attributed"));
+        let bytes = revert_data(&pair);
+        let back: (u8, String) = try_convert_data(&bytes).unwrap();
+        assert_eq!(back, pair);
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = revert_data((2u8, String::from("attributed")));
+        let ret: Result<(u8, String), _> = try_convert_data(&bytes[..bytes.len() - 1]);
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn garbage() {
+        let bytes = [0xffu8; 16];
+        let ret: Result<(u8, String), _> = try_convert_data(&bytes);
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn reuses_buffer() {
+        let pair = (2u8, String::from("attributed"));
+        let first = with_scratch(|buf| {
+            revert_data_into(&"supercalifragilisticexpialidocious".repeat(100), buf);
+            buf.as_ptr()
+        });
+        // The buffer keeps its allocation but not its contents
+        with_scratch(|buf| {
+            revert_data_into(&pair, buf);
+            assert_eq!(buf.as_ptr(), first);
+            assert_eq!(*buf, revert_data(&pair));
+        });
+        let mut buf = Vec::new();
+        revert_data_json_into(&pair, &mut buf);
+        revert_data_json_into(&pair, &mut buf);
+        assert_eq!(buf, revert_data_json(&pair));
+    }
+
+    // These look at the bytes of a payload, which are only
+    // laid out like this when it isn't compressed
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn version_mismatch() {
+        let mut bytes = revert_data((2u8, String::from("attributed")));
+        // A plugin built against some other version
+        bytes[3] += 1;
+        let e = try_convert_data::<(u8, String)>(&bytes).unwrap_err();
+        assert!(e.to_string().contains("expected wire header"));
+        let e = try_convert_data::<(u8, String)>(&bytes[..2]).unwrap_err();
+        assert!(e.to_string().contains("expected wire header"));
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn checksum() {
+        let bytes = revert_data((2u8, String::from("attributed")));
+        assert!(check_payload(&bytes).is_ok());
+        match check_payload(&bytes[..bytes.len() - 3]) {
+            Err(PluginError::Corrupt(msg)) => assert!(msg.contains("truncated")),
+            other => panic!("expected a corrupt payload, found {:?}", other),
+        }
+        let e = try_convert_data::<(u8, String)>(&bytes[..bytes.len() - 3]).unwrap_err();
+        assert!(e.to_string().contains("truncated"));
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn result_ok() {
+        let pair = (2u8, String::from("attributed"));
+        let bytes = revert_data(Ok::<_, String>(&pair));
+        // The variant comes after the header, 0 for Ok
+        assert_eq!(&bytes[..4], &WIRE_HEADER);
+        assert_eq!(&bytes[4..8], &[0, 0, 0, 0]);
+        let back: Result<(u8, String), String> = convert_data(&bytes);
+        assert_eq!(back, Ok(pair));
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn result_err() {
+        let msg = String::from("unknown directive in chapter 3");
+        let bytes = revert_data(Err::<(u8, String), _>(&msg));
+        // and 1 for Err
+        assert_eq!(&bytes[4..8], &[1, 0, 0, 0]);
+        let back: Result<(u8, String), String> = convert_data(&bytes);
+        assert_eq!(back, Err(msg));
+    }
+
+    #[test]
+    fn owned_outlives_buffer() {
+        let s: String = {
+            let bytes = revert_data("attributed");
+            convert_data_owned(&bytes)
+        };
+        assert_eq!(s, "attributed");
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn borrowed() {
+        let bytes = revert_data(("attributed", 2u8));
+        let (s, u): (&str, u8) = convert_data(&bytes);
+        assert_eq!((s, u), ("attributed", 2));
+        // Read straight out of the payload
+        assert!(bytes.as_ptr_range().contains(&s.as_ptr()));
+    }
+
+    // Run with `cargo test --release -- --ignored borrowed_vs_owned --nocapture`
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    #[ignore]
+    fn borrowed_vs_owned() {
+        use std::{
+            hint::black_box,
+            time::{
+                Duration,
+                Instant,
+            },
+        };
+        let chapters: Vec<String> = (0..10_000)
+            .map(|i| format!("Chapter {} is all about WASM. ", i).repeat(20))
+            .collect();
+        let bytes = revert_data(&chapters);
+        let time = |read: &dyn Fn()| -> Duration {
+            let start = Instant::now();
+            for _ in 0..20 {
+                read();
+            }
+            start.elapsed()
+        };
+        // What `plugin_helper` does with and without `borrow`
+        let owned = time(&|| {
+            black_box(convert_data::<Vec<String>>(&bytes));
+        });
+        let borrowed = time(&|| {
+            black_box(convert_data::<Vec<&str>>(&bytes));
+        });
+        println!("owned {:?}, borrowed {:?}", owned, borrowed);
+        assert!(borrowed < owned);
+    }
+
+    #[test]
+    fn host_env() {
+        assert_eq!(super::host_env(), None);
+        set_host_env(br#"{"sha": "1a4f38e"}"#);
+        assert_eq!(super::host_env().unwrap()["sha"], "1a4f38e");
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn wire_format() {
+        let bytes = revert_data((1u16, "ab"));
+        let body = &bytes[WIRE_HEADER.len()..bytes.len() - CHECKSUM_SIZE];
+        // The u16 in 2 bytes, then the string's
+        // length in 8 bytes followed by its bytes
+        assert_eq!(body, &[1, 0, 2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        let back: (u16, String) = convert_data(&bytes);
+        assert_eq!(back, (1, "ab".to_string()));
+    }
+
+    #[cfg(not(feature = "compress"))]
+    #[test]
+    fn error_offset() {
+        let mut bytes = revert_data((1u16, true));
+        // Anything but 0 or 1 isn't a bool, with the
+        // checksum redone so the payload still checks out
+        bytes.truncate(bytes.len() - CHECKSUM_SIZE);
+        bytes[WIRE_HEADER.len() + 2] = 2;
+        append_checksum(&mut bytes);
+        let e = try_convert_data_owned::<(u16, bool)>(&bytes).unwrap_err();
+        assert!(e.to_string().ends_with("at byte 7 of 11"), "no offset in {:?}", e.to_string());
+    }
+
+    #[test]
+    fn host_args() {
+        assert!(super::host_args().is_empty());
+        set_host_args(br#"["--theme", "dark"]"#);
+        assert_eq!(super::host_args(), vec!["--theme", "dark"]);
+    }
+
+    #[test]
+    fn plugin_context() {
+        assert_eq!(with_plugin_context(String::clone), None);
+        set_plugin_context(String::from("html"));
+        assert_eq!(with_plugin_context(String::clone), Some(String::from("html")));
+        assert_eq!(with_plugin_context(|n: &u8| *n), None);
+        // The next call's context replaces it
+        set_plugin_context(String::from("epub"));
+        assert_eq!(with_plugin_context(String::len), Some(4));
+    }
+
+    #[test]
+    fn host_seed() {
+        assert_eq!(super::host_seed(), None);
+        set_host_seed(42);
+        assert_eq!(super::host_seed(), Some(42));
+    }
+
+    #[test]
+    fn panic_hook() {
+        install_panic_hook();
+        install_panic_hook();
+        let _ = panic::catch_unwind(|| panic!("chapter {} is missing", 3));
+        assert_eq!(panic_message(), "chapter 3 is missing");
+        let _ = panic::catch_unwind(|| panic!("no summary"));
+        assert_eq!(panic_message(), "no summary");
+        install_panic_hook();
+        assert_eq!(panic_message(), "");
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let s = "supercalifragilisticexpialidocious".repeat(100);
+        let bincode = revert_data(&s);
+        let json = revert_data_json(&s);
+        if cfg!(feature = "compress") {
+            assert!(bincode.len() < s.len());
+            assert!(json.len() < s.len());
+        } else {
+            assert!(bincode.len() > s.len());
+            assert!(json.len() > s.len());
+        }
+        assert_eq!(convert_data_owned::<String>(&bincode), s);
+        assert_eq!(convert_data_json::<String>(&json), s);
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S>(&self, _: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer {
+            Err(serde::ser::Error::custom("nope"))
+        }
+    }
+
+    #[test]
+    fn serialize_error() {
+        let e = try_revert_data(Unserializable).unwrap_err();
+        assert!(e.to_string().contains("nope"));
+    }
 }
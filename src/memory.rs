@@ -0,0 +1,46 @@
+// ./src/memory.rs
+use std::cell::Cell;
+
+/// Copy `bytes` into `cells` in one go rather than
+/// setting each cell in turn.
+///
+/// Panics if the two are not the same length
+pub fn write_bytes(cells: &[Cell<u8>], bytes: &[u8]) {
+    // `Cell<u8>` has the same layout as `u8` and it is fine
+    // to write to a cell through a shared reference, nothing
+    // else can see `cells` while we hold this slice
+    let dest = unsafe {
+        ::std::slice::from_raw_parts_mut(cells.as_ptr() as *mut u8, cells.len())
+    };
+    dest.copy_from_slice(bytes);
+}
+
+/// Copy the contents of `cells` out in one go rather
+/// than getting each cell in turn
+pub fn read_bytes(cells: &[Cell<u8>]) -> Vec<u8> {
+    let src = unsafe {
+        ::std::slice::from_raw_parts(cells.as_ptr() as *const u8, cells.len())
+    };
+    src.to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let cells: Vec<Cell<u8>> = (0..16).map(|_| Cell::new(0)).collect();
+        let bytes: Vec<u8> = (0..8).collect();
+        write_bytes(&cells[4..12], &bytes);
+        assert!(cells[..4].iter().chain(&cells[12..]).all(|c| c.get() == 0));
+        assert_eq!(read_bytes(&cells[4..12]), bytes);
+    }
+
+    #[test]
+    #[should_panic]
+    fn length_mismatch() {
+        let cells: Vec<Cell<u8>> = (0..4).map(|_| Cell::new(0)).collect();
+        write_bytes(&cells, &[1, 2, 3]);
+    }
+}
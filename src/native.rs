@@ -0,0 +1,123 @@
+// ./src/native.rs
+use serde::{
+    Serialize,
+    de::DeserializeOwned,
+};
+use std::collections::HashMap;
+use crate::{
+    host::{
+        decode_output,
+        encode_input,
+    },
+    PluginError,
+    SerializationFormat,
+};
+
+type NativeFunc = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, PluginError>>;
+
+/// Plugin functions linked straight into the host instead of
+/// being loaded from wasm, for tests and trusted plugins.
+///
+/// Every call goes through the same serialization as a call to
+/// a `PluginInstance`, so a function behaves the same either way
+#[derive(Default)]
+pub struct NativePlugin {
+    format: SerializationFormat,
+    funcs: HashMap<String, NativeFunc>,
+}
+
+impl NativePlugin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The format data is sent in, this should match the
+    /// `PluginInstance` it stands in for. Functions use the
+    /// format set when they were registered
+    pub fn with_format(mut self, format: SerializationFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Export `f` as `name`, the same name the wasm build would
+    /// export it as. A function taking more than one argument
+    /// takes them as a tuple
+    pub fn register<I, O, F>(mut self, name: &str, f: F) -> Self
+    where I: DeserializeOwned,
+          O: Serialize,
+          F: Fn(I) -> O + 'static {
+        let format = self.format;
+        // This is the plugin's side of the call, the
+        // same steps a `plugin_helper` shadow takes
+        let func = move |bytes: &[u8]| {
+            let arg: I = format.deserialize_owned(bytes)
+                .map_err(|e| PluginError::Serde(e.to_string()))?;
+            let ret: Result<O, String> = Ok(f(arg));
+            format.serialize(&ret)
+                .map_err(|e| PluginError::Serde(e.to_string()))
+        };
+        self.funcs.insert(name.to_string(), Box::new(func));
+        self
+    }
+
+    /// The names of every registered function
+    pub fn exports(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.funcs.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Call the function registered as `name`
+    /// with `input`, like `PluginInstance::call`
+    pub fn call<I, O>(&self, name: &str, input: I) -> Result<O, PluginError>
+    where I: Serialize,
+          O: DeserializeOwned {
+        let func = self.funcs.get(name)
+            .ok_or_else(|| PluginError::MissingExport(name.to_string()))?;
+        let bytes = encode_input(self.format, input)?;
+        decode_output(self.format, &func(&bytes)?)?
+            .map_err(PluginError::Plugin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn multiply(pair: (u8, String)) -> (u8, String) {
+        let u = pair.0.wrapping_mul(2);
+        (u, pair.1.repeat(u as usize))
+    }
+
+    #[test]
+    fn call() {
+        let plugin = NativePlugin::new()
+            .register("_multiply", multiply);
+        assert_eq!(plugin.exports(), vec!["_multiply"]);
+        let pair = (2u8, String::from("attributed"));
+        let updated: (u8, String) = plugin.call("_multiply", &pair).unwrap();
+        assert_eq!(updated, (4, "attributed".repeat(4)));
+    }
+
+    #[test]
+    fn format() {
+        let plugin = NativePlugin::new()
+            .with_format(SerializationFormat::Json)
+            .register("_multiply", multiply);
+        let updated: (u8, String) = plugin.call("_multiply", (1u8, "a")).unwrap();
+        assert_eq!(updated, (2, String::from("aa")));
+    }
+
+    #[test]
+    fn errors() {
+        let plugin = NativePlugin::new()
+            .register("_multiply", multiply);
+        match plugin.call::<_, (u8, String)>("_missing", ()) {
+            Err(PluginError::MissingExport(name)) => assert_eq!(name, "_missing"),
+            other => panic!("expected a missing export, found {:?}", other),
+        }
+        // Sent the wrong type, the same as a wasm plugin
+        // that can't deserialize its input
+        assert!(plugin.call::<_, (u8, String)>("_multiply", ()).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+// ./src/plugin_host.rs
+use serde_json::Value;
+use std::time::Duration;
+use wasmer_runtime::{
+    ImportObject,
+    Module,
+};
+use crate::{
+    host::{
+        host_imports,
+        run_with_timeout,
+    },
+    PluginError,
+    PluginInstance,
+    SerializationFormat,
+};
+
+/// Settings shared by every plugin a host runs,
+/// created with `PluginHost::builder`
+#[derive(Clone, Debug)]
+pub struct PluginHost {
+    format: SerializationFormat,
+    timeout: Option<Duration>,
+    env: Option<Value>,
+    seed: Option<u64>,
+    args: Vec<String>,
+    max_payload_bytes: usize,
+    type_checks: bool,
+    // A function instead of an `ImportObject` so the
+    // host can be sent to the thread a plugin runs on
+    imports: fn() -> ImportObject,
+}
+
+impl Default for PluginHost {
+    fn default() -> Self {
+        Self {
+            format: SerializationFormat::default(),
+            timeout: None,
+            env: None,
+            seed: None,
+            args: Vec::new(),
+            max_payload_bytes: PluginInstance::DEFAULT_MAX_PAYLOAD_BYTES,
+            type_checks: false,
+            imports: host_imports,
+        }
+    }
+}
+
+/// Configures a `PluginHost`
+#[derive(Clone, Debug, Default)]
+pub struct PluginHostBuilder {
+    host: PluginHost,
+}
+
+impl PluginHostBuilder {
+    /// The format every plugin's data is sent in
+    pub fn with_format(mut self, format: SerializationFormat) -> Self {
+        self.host.format = format;
+        self
+    }
+
+    /// How long a plugin gets before `PluginHost::run`
+    /// gives up on it
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.host.timeout = Some(timeout);
+        self
+    }
+
+    /// Values every plugin can read with `host_env`, such
+    /// as a build timestamp or git sha. They are handed
+    /// over when each plugin is instantiated
+    pub fn with_env(mut self, env: Value) -> Self {
+        self.host.env = Some(env);
+        self
+    }
+
+    /// The seed every plugin can read with `host_seed`, the
+    /// same seed should give the same output from a plugin
+    /// that uses randomness
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.host.seed = Some(seed);
+        self
+    }
+
+    /// Arguments every plugin can read with `host_args`,
+    /// like the runner's arguments after `--`
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.host.args = args;
+        self
+    }
+
+    /// The most bytes a plugin can be sent in one input,
+    /// anything larger fails with `PluginError::TooLarge`
+    pub fn with_max_payload_bytes(mut self, max: usize) -> Self {
+        self.host.max_payload_bytes = max;
+        self
+    }
+
+    /// If every plugin checks the type it is sent against the
+    /// one its function expects before a call, see
+    /// `PluginInstance::check_input`
+    pub fn with_type_checks(mut self, type_checks: bool) -> Self {
+        self.host.type_checks = type_checks;
+        self
+    }
+
+    /// The imports every plugin is instantiated with,
+    /// `host_imports` by default
+    pub fn with_imports(mut self, imports: fn() -> ImportObject) -> Self {
+        self.host.imports = imports;
+        self
+    }
+
+    pub fn build(self) -> PluginHost {
+        self.host
+    }
+}
+
+impl PluginHost {
+    pub fn builder() -> PluginHostBuilder {
+        PluginHostBuilder::default()
+    }
+
+    pub fn format(&self) -> SerializationFormat {
+        self.format
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn env(&self) -> Option<&Value> {
+        self.env.as_ref()
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn max_payload_bytes(&self) -> usize {
+        self.max_payload_bytes
+    }
+
+    pub fn type_checks(&self) -> bool {
+        self.type_checks
+    }
+
+    /// A copy of this host that instantiates
+    /// plugins with `imports` instead
+    pub fn with_imports(&self, imports: fn() -> ImportObject) -> Self {
+        Self {
+            imports,
+            ..self.clone()
+        }
+    }
+
+    /// Instantiate a compiled module with the host's imports,
+    /// format, payload limit and type checks, then pass it the
+    /// host's environment, seed and arguments if there are any
+    pub fn instantiate(&self, module: &Module) -> Result<PluginInstance, PluginError> {
+        let instance = PluginInstance::from_module_with_imports(module, &(self.imports)())?
+            .with_format(self.format)
+            .with_max_payload_bytes(self.max_payload_bytes)
+            .with_type_checks(self.type_checks);
+        if let Some(env) = &self.env {
+            instance.set_env(env)?;
+        }
+        if let Some(seed) = self.seed {
+            instance.set_seed(seed)?;
+        }
+        if !self.args.is_empty() {
+            instance.set_args(&self.args)?;
+        }
+        Ok(instance)
+    }
+
+    /// Run `f` under this host's timeout, without a timeout it
+    /// runs on the current thread. With one it runs on its own
+    /// thread so anything it instantiates needs to happen
+    /// inside of `f`
+    pub fn run<F, R>(&self, name: &str, f: F) -> Result<R, PluginError>
+    where F: FnOnce() -> Result<R, PluginError> + Send + 'static,
+          R: Send + 'static {
+        match self.timeout {
+            Some(_) => run_with_timeout(name, self.timeout, f),
+            None => f(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use wasmer_runtime::{
+        compile,
+        func,
+        imports,
+        Ctx,
+    };
+
+    #[test]
+    fn defaults() {
+        let host = PluginHost::builder().build();
+        assert_eq!(host.format(), SerializationFormat::Bincode);
+        assert_eq!(host.timeout(), None);
+        assert_eq!(host.env(), None);
+        assert_eq!(host.seed(), None);
+        assert!(host.args().is_empty());
+        assert_eq!(host.max_payload_bytes(), PluginInstance::DEFAULT_MAX_PAYLOAD_BYTES);
+        assert!(!host.type_checks());
+        assert_eq!(host.run("quick", || Ok(1)), Ok(1));
+    }
+
+    #[test]
+    fn seed() {
+        // Steps a xorshift generator from the seed it was handed
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (global $state (mut i64) (i64.const 1))
+                (func (export "_set_seed") (param i64)
+                    get_local 0
+                    set_global $state)
+                (func (export "next") (result i64)
+                    (set_global $state (i64.xor (get_global $state) (i64.shl (get_global $state) (i64.const 13))))
+                    (set_global $state (i64.xor (get_global $state) (i64.shr_u (get_global $state) (i64.const 7))))
+                    (set_global $state (i64.xor (get_global $state) (i64.shl (get_global $state) (i64.const 17))))
+                    get_global $state))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let run = |host: &PluginHost| {
+            let plugin = host.instantiate(&module).unwrap();
+            let next = plugin.instance().func::<(), u64>("next").unwrap();
+            (0..4).map(|_| next.call().unwrap()).collect::<Vec<u64>>()
+        };
+        let host = PluginHost::builder().with_seed(1234).build();
+        assert_eq!(host.seed(), Some(1234));
+        assert_eq!(run(&host), run(&host));
+        assert_ne!(run(&host), run(&PluginHost::builder().with_seed(4321).build()));
+    }
+
+    /// A plugin that keeps what the setter `setter` is handed
+    /// at address 4096, returning a copy of it once instantiated
+    fn handed_over(host: &PluginHost, setter: &str) -> Vec<u8> {
+        let wasm = wabt::wat2wasm(format!(r#"
+            (module
+                (memory 1)
+                (global $len (mut i32) (i32.const 0))
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "{}") (param $ptr i32) (param $len i32)
+                    (local $i i32)
+                    (block $done
+                        (loop $copy
+                            (br_if $done (i32.ge_u (get_local $i) (get_local $len)))
+                            (i32.store8
+                                (i32.add (i32.const 4096) (get_local $i))
+                                (i32.load8_u (i32.add (get_local $ptr) (get_local $i))))
+                            (set_local $i (i32.add (get_local $i) (i32.const 1)))
+                            (br $copy)))
+                    (set_global $len (get_local $len)))
+                (func (export "handed_len") (result i32)
+                    get_global $len))
+        "#, setter)).unwrap();
+        let plugin = host.instantiate(&compile(&wasm).unwrap()).unwrap();
+        let len = plugin.instance().func::<(), i32>("handed_len").unwrap().call().unwrap() as usize;
+        let view = plugin.instance().context().memory(0).view::<u8>();
+        view[4096..4096 + len].iter().map(|c| c.get()).collect()
+    }
+
+    #[test]
+    fn env() {
+        let host = PluginHost::builder()
+            .with_env(serde_json::json!({"sha": "1a4f38e"}))
+            .build();
+        let seen: Value = serde_json::from_slice(&handed_over(&host, "_set_env")).unwrap();
+        assert_eq!(seen["sha"], "1a4f38e");
+    }
+
+    #[test]
+    fn args() {
+        let host = PluginHost::builder()
+            .with_args(vec!["--dark".to_string()])
+            .build();
+        assert_eq!(host.args(), ["--dark".to_string()]);
+        let seen: Vec<String> = serde_json::from_slice(&handed_over(&host, "_set_args")).unwrap();
+        assert_eq!(seen, vec!["--dark"]);
+    }
+
+    /// The standard imports along with one that
+    /// answers every question with 42
+    fn answer_imports() -> ImportObject {
+        imports! {
+            "env" => {
+                "host_log" => func!(crate::host::host_log),
+                "host_answer" => func!(host_answer),
+            },
+        }
+    }
+
+    fn host_answer(_ctx: &mut Ctx) -> i32 {
+        42
+    }
+
+    #[test]
+    fn imports() {
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (import "env" "host_answer" (func $host_answer (result i32)))
+                (memory 1)
+                (func (export "answer") (result i32)
+                    call $host_answer))
+        "#).unwrap();
+        let module = compile(&wasm).unwrap();
+        let host = PluginHost::builder()
+            .with_imports(answer_imports)
+            .build();
+        let plugin = host.instantiate(&module).unwrap();
+        let answer = plugin.instance().func::<(), i32>("answer").unwrap().call().unwrap();
+        assert_eq!(answer, 42);
+        // The standard imports don't have it
+        match PluginHost::default().instantiate(&module) {
+            Err(PluginError::Instantiate(_)) => (),
+            other => panic!("expected an instantiate error, found {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn format() {
+        let host = PluginHost::builder()
+            .with_format(SerializationFormat::Json)
+            .build();
+        // Echoes its input back with an allocator
+        // that always points at address 16
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "_echo") (param i32 i32) (result i32)
+                    i32.const 0
+                    get_local 1
+                    i32.store
+                    get_local 0))
+        "#).unwrap();
+        let plugin = host.instantiate(&compile(&wasm).unwrap()).unwrap();
+        // Json is sent as is, so a json string echoed
+        // back is a valid `Ok` for serde_json
+        let back: Result<String, _> = plugin.call("_echo", serde_json::json!({"Ok": "attributed"}));
+        assert_eq!(back, Ok(String::from("attributed")));
+    }
+
+    #[test]
+    fn type_checks() {
+        let host = PluginHost::builder()
+            .with_type_checks(true)
+            .build();
+        assert!(host.type_checks());
+        // Expects a `u8`, echoing whatever it is sent
+        let wasm = wabt::wat2wasm(r#"
+            (module
+                (memory 1)
+                (data (i32.const 2048) "u8")
+                (func (export "__plugin_alloc") (param i32) (result i32)
+                    i32.const 16)
+                (func (export "__plugin_free") (param i32 i32))
+                (func (export "__input_type_of_echo") (result i32)
+                    i32.const 0
+                    i32.const 2
+                    i32.store
+                    i32.const 2048)
+                (func (export "_echo") (param i32 i32) (result i32)
+                    i32.const 0
+                    get_local 1
+                    i32.store
+                    get_local 0))
+        "#).unwrap();
+        let plugin = host.instantiate(&compile(&wasm).unwrap()).unwrap();
+        match plugin.call::<_, String>("_echo", "attributed") {
+            Err(PluginError::TypeMismatch(_)) => (),
+            other => panic!("expected a type mismatch, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let host = PluginHost::builder()
+            .with_timeout(Duration::from_millis(50))
+            .build();
+        let ret = host.run("slow", || {
+            thread::sleep(Duration::from_millis(1000));
+            Ok(())
+        });
+        match ret {
+            Err(PluginError::Timeout(msg)) => assert!(msg.starts_with("slow")),
+            other => panic!("expected a timeout, found {:?}", other),
+        }
+        assert_eq!(host.run("quick", || Ok(1)), Ok(1));
+    }
+}
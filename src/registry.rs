@@ -0,0 +1,27 @@
+// ./src/registry.rs
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+
+/// A function a plugin exports, as listed by
+/// `plugin_registry!`. The types are the names
+/// as written in the plugin's source
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginExport {
+    /// The exported symbol the host calls
+    pub name: Cow<'static, str>,
+    /// The type the function's input is deserialized as
+    pub input: Cow<'static, str>,
+    /// The type of the function's output
+    pub output: Cow<'static, str>,
+}
+
+impl PluginExport {
+    /// Used by `plugin_helper` to describe a function
+    pub const fn new(name: &'static str, input: &'static str, output: &'static str) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            input: Cow::Borrowed(input),
+            output: Cow::Borrowed(output),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// ./tests/scratch_allocations.rs
+//! How many allocations serializing a plugin's output takes
+//! with and without `with_scratch`, this is its own test
+//! binary so the counting allocator only sees this test
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use wasmer_plugin_example::{revert_data, revert_data_into, with_scratch};
+
+/// The system allocator, counting every allocation
+/// and reallocation it is asked for
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The allocations `f` makes
+fn count(f: impl FnOnce()) -> usize {
+    let start = ALLOCATIONS.load(Ordering::SeqCst);
+    f();
+    ALLOCATIONS.load(Ordering::SeqCst) - start
+}
+
+// Run with `cargo test --release --test scratch_allocations -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn scratch_allocations() {
+    let chapters: Vec<String> = (0..100)
+        .map(|i| format!("Chapter {} is all about WASM. ", i).repeat(20))
+        .collect();
+    // What `plugin_helper` did for every call before,
+    // handing the host a boxed slice
+    let owned = count(|| for _ in 0..1_000 {
+        let bytes = revert_data(&chapters).into_boxed_slice();
+        drop(bytes);
+    });
+    // What it does now, the first call grows the buffer
+    // which every call after that reuses and the output
+    // is boxed straight from it
+    let scratch = count(|| for _ in 0..1_000 {
+        let bytes: Box<[u8]> = with_scratch(|buf| {
+            revert_data_into(&chapters, buf);
+            Box::from(buf.as_slice())
+        });
+        drop(bytes);
+    });
+    println!("owned {} allocations, scratch {} allocations", owned, scratch);
+    assert!(scratch < owned);
+}